@@ -0,0 +1,566 @@
+//!
+//! A textual assembly format of programs.
+//!
+//! A program is a sequence of instructions separated by any whitespace, so
+//! instructions can be written one per line or a few per line. Mnemonics are
+//! case-insensitive: `push <v>`, `or`, `and`, `not`, `xor`, `dup`, `pop`,
+//! `swap`, `if`, `else`, `begin`, `loop`, `break`, `end` and `call <n>`, where
+//! `<v>` is an operand: `true` or `false`, a decimal integer like `-7` or a
+//! symbol like `$x` (see [`Value`] for escapes in names), and `<n>` is a
+//! registry index. A comment starts with `;` and lasts up to the end of the
+//! line.
+//!
+//! Registry patterns use the same syntax with a few additions: `push _` matches
+//! any operand, `push _:int` matches any operand of the type (`bool`, `int` or
+//! `symbol`), `push 0..10` matches integers in the range, `push ?x` captures
+//! the operand by the name `x` (escaped as a symbol name), `push =<v>` matches
+//! exactly the operand like `push <v>` does, `<block>` matches any nested block
+//! and `...` matches any sequence of instructions.
+//!
+//! ```text
+//! begin           ; the outer block
+//!   if push 2 end
+//!   push 1
+//! end
+//! ```
+//!
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::iter;
+
+use crate::delimiter::BlockDelimiter;
+use crate::delimiter::Delimiter;
+use crate::value;
+use crate::Instruction;
+use crate::Operand;
+use crate::Token;
+use crate::Value;
+use crate::ValueType;
+
+/// The comment marker, all characters after it up to the end of the line are
+/// ignored.
+pub(crate) const COMMENT: char = ';';
+
+/// An error occurred while parsing a text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// A line of the error, starting from 1
+    pub line: usize,
+    /// A column of the error, starting from 1
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// The word isn't a known mnemonic.
+    UnknownMnemonic(String),
+    /// The instruction requires an operand, but the text is over.
+    MissingOperand,
+    /// The word isn't a valid operand.
+    InvalidOperand(String),
+    /// The registry file doesn't start with the schema version.
+    MissingVersion,
+    /// The schema version of the registry file isn't supported.
+    UnsupportedVersion(String),
+    /// The registry entry has no name.
+    MissingEntryName,
+    /// The registry entry name is already used.
+    DuplicateEntry(String),
+    /// The word of the registry file doesn't belong to any entry.
+    OutsideEntry,
+}
+
+/// A single whitespace-separated word of a text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Word<'a> {
+    pub text: &'a str,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl<'a> Word<'a> {
+    /// Creates the error at the position of this word.
+    pub(crate) fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            kind,
+        }
+    }
+}
+
+/// Splits the text into words, skipping comments.
+pub(crate) fn words(text: &str) -> Vec<Word<'_>> {
+    text.lines()
+        .enumerate()
+        .flat_map(|(line_idx, line)| line_words(line, line_idx + 1))
+        .collect()
+}
+
+/// Splits a single line of a text into words, skipping a comment.
+///
+/// # Arguments
+///
+/// * line - The line without a line break.
+/// * line_no - The number of the line in the whole text, starting from 1.
+///
+pub(crate) fn line_words(line: &str, line_no: usize) -> Vec<Word<'_>> {
+    let code = line.split(COMMENT).next().unwrap_or_default();
+    let mut words = Vec::new();
+    let mut word_start = None;
+    // a trailing whitespace finishes the last word of the line
+    let chars = code.char_indices().chain(iter::once((code.len(), ' ')));
+
+    for (column, (byte_idx, ch)) in chars.enumerate() {
+        match (ch.is_whitespace(), word_start) {
+            (false, None) => word_start = Some((byte_idx, column)),
+            (true, Some((start_byte_idx, start_column))) => {
+                words.push(Word {
+                    text: &code[start_byte_idx..byte_idx],
+                    line: line_no,
+                    column: start_column + 1,
+                });
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    words
+}
+
+/// Parses the program from its textual assembly form.
+pub fn parse_program(text: &str) -> Result<Vec<Instruction>, ParseError> {
+    let mut words = words(text).into_iter();
+    let mut program = Vec::new();
+
+    while let Some(word) = words.next() {
+        program.push(parse_instruction(word, &mut words)?);
+    }
+
+    Ok(program)
+}
+
+/// Parses the instruction which starts with the word, takes an operand from
+/// the rest of words if the instruction requires one.
+pub(crate) fn parse_instruction<'a>(
+    word: Word<'a>,
+    rest: &mut impl Iterator<Item = Word<'a>>,
+) -> Result<Instruction, ParseError> {
+    use crate::Instruction::*;
+
+    let instruction = match word.text.to_lowercase().as_str() {
+        "push" => {
+            let operand = rest
+                .next()
+                .ok_or_else(|| word.error(ParseErrorKind::MissingOperand))?;
+            let value = Value::parse(operand.text).ok_or_else(|| {
+                operand.error(ParseErrorKind::InvalidOperand(operand.text.to_string()))
+            })?;
+            Push(value)
+        }
+        "or" => Or,
+        "and" => And,
+        "not" => Not,
+        "if" => If,
+        "begin" => Begin,
+        "end" => End,
+        "else" => Else,
+        "loop" => Loop,
+        "break" => Break,
+        "xor" => Xor,
+        "dup" => Dup,
+        "pop" => Pop,
+        "swap" => Swap,
+        "call" => {
+            let operand = rest
+                .next()
+                .ok_or_else(|| word.error(ParseErrorKind::MissingOperand))?;
+            let registry_idx = operand.text.parse().map_err(|_| {
+                operand.error(ParseErrorKind::InvalidOperand(operand.text.to_string()))
+            })?;
+            Call(registry_idx)
+        }
+        _ => {
+            return Err(word.error(ParseErrorKind::UnknownMnemonic(word.text.to_string())));
+        }
+    };
+
+    Ok(instruction)
+}
+
+/// Parses the registry pattern from its textual form.
+pub fn parse_pattern(text: &str) -> Result<Vec<Token>, ParseError> {
+    let mut words = words(text).into_iter();
+    let mut pattern = Vec::new();
+
+    while let Some(word) = words.next() {
+        pattern.push(parse_token(word, &mut words)?);
+    }
+
+    Ok(pattern)
+}
+
+/// Parses the pattern token which starts with the word, takes an operand
+/// from the rest of words if the token requires one.
+pub(crate) fn parse_token<'a>(
+    word: Word<'a>,
+    rest: &mut impl Iterator<Item = Word<'a>>,
+) -> Result<Token, ParseError> {
+    match word.text.to_lowercase().as_str() {
+        ANY_BLOCK => Ok(Token::AnyBlock),
+        ANY_SEQUENCE => Ok(Token::AnySequence),
+        "push" => {
+            let operand = rest
+                .next()
+                .ok_or_else(|| word.error(ParseErrorKind::MissingOperand))?;
+            let invalid =
+                || operand.error(ParseErrorKind::InvalidOperand(operand.text.to_string()));
+            let parse_int = |text: &str| text.parse::<i64>().map_err(|_| invalid());
+
+            let operand = if operand.text == ANY_OPERAND {
+                Operand::Any
+            } else if let Some(name) = operand.text.strip_prefix(ANY_OF_TYPE) {
+                Operand::Type(ValueType::parse(name).ok_or_else(invalid)?)
+            } else if let Some(name) = operand.text.strip_prefix(CAPTURE) {
+                if name.is_empty() {
+                    return Err(invalid());
+                }
+                Operand::Capture(value::unescape(name).ok_or_else(invalid)?)
+            } else if let Some(value) = operand.text.strip_prefix(EXACT) {
+                Operand::Exact(Value::parse(value).ok_or_else(invalid)?)
+            } else if let Some(value) = Value::parse(operand.text) {
+                return Ok(Token::Ins(Instruction::Push(value)));
+            } else if let Some((start, end)) = operand.text.split_once(RANGE) {
+                Operand::Range(parse_int(start)?..parse_int(end)?)
+            } else {
+                return Err(invalid());
+            };
+            Ok(Token::Push(operand))
+        }
+        _ => parse_instruction(word, rest).map(Token::Ins),
+    }
+}
+
+/// Displays a program in the textual assembly form, one instruction per line,
+/// instructions inside blocks are indented by the block depth.
+pub struct Listing<'a>(pub &'a [Instruction]);
+
+/// Displays a registry pattern in the textual form, the same way as
+/// [`Listing`] displays a program.
+pub struct PatternListing<'a>(pub &'a [Token]);
+
+/// A number of spaces per a single depth level.
+const INDENT: usize = 2;
+
+const ANY_OPERAND: &str = "_";
+const ANY_OF_TYPE: &str = "_:";
+const CAPTURE: char = '?';
+const EXACT: char = '=';
+const RANGE: &str = "..";
+const ANY_BLOCK: &str = "<block>";
+const ANY_SEQUENCE: &str = "...";
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write_indented(f, self.0.iter(), |instruction| Some(instruction))
+    }
+}
+
+impl Display for PatternListing<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write_indented(f, self.0.iter(), |token| match token {
+            Token::Ins(instruction) => Some(instruction),
+            _ => None,
+        })
+    }
+}
+
+/// Writes items one per line, items inside blocks are indented by the block
+/// depth. `as_instruction` returns the instruction of an item, if any.
+fn write_indented<'a, T: Display + 'a>(
+    f: &mut Formatter,
+    items: impl Iterator<Item = &'a T>,
+    as_instruction: impl Fn(&T) -> Option<&Instruction>,
+) -> fmt::Result {
+    let mut depth = 0usize;
+    for item in items {
+        let delimiter = as_instruction(item).map(Instruction::delimiter);
+        if let Some(Delimiter::Close) | Some(Delimiter::Split) = delimiter {
+            depth = depth.saturating_sub(1);
+        }
+        writeln!(f, "{:indent$}{}", "", item, indent = depth * INDENT)?;
+        if let Some(Delimiter::Open) | Some(Delimiter::Split) = delimiter {
+            depth += 1;
+        }
+    }
+    Ok(())
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Token::Ins(instruction) => write!(f, "{}", instruction),
+            Token::Push(operand) => write!(f, "push {}", operand),
+            Token::AnyBlock => write!(f, "{}", ANY_BLOCK),
+            Token::AnySequence => write!(f, "{}", ANY_SEQUENCE),
+        }
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Operand::Exact(value) => write!(f, "{}{}", EXACT, value),
+            Operand::Any => write!(f, "{}", ANY_OPERAND),
+            Operand::Type(value_type) => write!(f, "{}{}", ANY_OF_TYPE, value_type),
+            Operand::Range(range) => write!(f, "{}{}{}", range.start, RANGE, range.end),
+            Operand::Capture(name) => {
+                write!(f, "{}", CAPTURE)?;
+                value::write_name(f, name)
+            }
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        use crate::Instruction::*;
+
+        match self {
+            Push(value) => write!(f, "push {}", value),
+            Or => write!(f, "or"),
+            And => write!(f, "and"),
+            Not => write!(f, "not"),
+            If => write!(f, "if"),
+            Begin => write!(f, "begin"),
+            End => write!(f, "end"),
+            Else => write!(f, "else"),
+            Loop => write!(f, "loop"),
+            Break => write!(f, "break"),
+            Xor => write!(f, "xor"),
+            Dup => write!(f, "dup"),
+            Pop => write!(f, "pop"),
+            Swap => write!(f, "swap"),
+            Call(registry_idx) => write!(f, "call {}", registry_idx),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Parse error at {}:{}: ", self.line, self.column)?;
+        match &self.kind {
+            ParseErrorKind::UnknownMnemonic(word) => write!(f, "unknown mnemonic '{}'", word),
+            ParseErrorKind::MissingOperand => write!(f, "missing operand"),
+            ParseErrorKind::InvalidOperand(word) => write!(f, "invalid operand '{}'", word),
+            ParseErrorKind::MissingVersion => write!(f, "missing registry schema version"),
+            ParseErrorKind::UnsupportedVersion(word) => {
+                write!(f, "unsupported registry schema version '{}'", word)
+            }
+            ParseErrorKind::MissingEntryName => write!(f, "missing entry name"),
+            ParseErrorKind::DuplicateEntry(name) => write!(f, "duplicate entry '{}'", name),
+            ParseErrorKind::OutsideEntry => write!(f, "instruction outside of any entry"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::parse_pattern;
+    use crate::asm::parse_program;
+    use crate::asm::Listing;
+    use crate::asm::ParseError;
+    use crate::asm::ParseErrorKind;
+    use crate::asm::PatternListing;
+    use crate::Instruction::*;
+    use crate::Operand;
+    use crate::Token;
+    use crate::Value::*;
+    use crate::ValueType;
+
+    #[test]
+    fn parse_with_comments() {
+        let text = "
+            ; a comment line
+            BEGIN if push 2 ; a trailing comment
+              Push 3 not
+            end End
+        ";
+
+        let program = parse_program(text).unwrap();
+
+        assert_eq!(
+            vec![Begin, If, Push(Int(2)), Push(Int(3)), Not, End, End],
+            program
+        );
+        assert_eq!(Ok(vec![]), parse_program(" ; nothing\n"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Err(ParseError {
+                line: 2,
+                column: 7,
+                kind: ParseErrorKind::UnknownMnemonic("nop".into()),
+            }),
+            parse_program("begin\nnot   nop end")
+        );
+        assert_eq!(
+            Err(ParseError {
+                line: 1,
+                column: 12,
+                kind: ParseErrorKind::InvalidOperand("$".into()),
+            }),
+            parse_program("begin push $ end")
+        );
+        assert_eq!(
+            Err(ParseError {
+                line: 1,
+                column: 12,
+                kind: ParseErrorKind::InvalidOperand("-1".into()),
+            }),
+            parse_program("begin call -1 end")
+        );
+        assert_eq!(
+            "Parse error at 1:7: missing operand",
+            parse_program("begin push").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn print_and_parse_round_trip() {
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(2)),
+            Push(Int(3)),
+            End,
+            If,
+            If,
+            End,
+            Push(Int(1)),
+            End,
+            Or,
+            And,
+            End,
+        ];
+
+        let text = Listing(&program).to_string();
+
+        assert_eq!(
+            "begin\n  if\n    push 2\n    push 3\n  end\n  if\n    if\n    end\n    push 1\n  end\n  or\n  and\nend\n",
+            text
+        );
+        assert_eq!(program, parse_program(&text).unwrap());
+    }
+
+    #[test]
+    fn print_and_parse_arms_and_loops() {
+        let program = vec![
+            Loop,
+            Dup,
+            If,
+            Pop,
+            Else,
+            Swap,
+            Xor,
+            Call(3),
+            Break,
+            End,
+            End,
+        ];
+
+        let text = Listing(&program).to_string();
+
+        assert_eq!(
+            "loop\n  dup\n  if\n    pop\n  else\n    swap\n    xor\n    call 3\n    break\n  end\nend\n",
+            text
+        );
+        assert_eq!(program, parse_program(&text).unwrap());
+    }
+
+    #[test]
+    fn typed_operands() {
+        let program = vec![
+            Begin,
+            Push(Bool(true)),
+            Push(Bool(false)),
+            Push(Int(-7)),
+            Push(Symbol("counter".into())),
+            Push(Symbol("a;b".into())),
+            Push(Symbol("x y".into())),
+            End,
+        ];
+
+        let text = Listing(&program).to_string();
+
+        assert_eq!(
+            "begin\n  push true\n  push false\n  push -7\n  push $counter\n  push $a\\u{3b}b\n  push $x\\u{20}y\nend\n",
+            text
+        );
+        assert_eq!(program, parse_program(&text).unwrap());
+        assert_eq!(
+            Ok(vec![Token::Ins(Push(Symbol("a..b".into())))]),
+            parse_pattern("push $a..b")
+        );
+    }
+
+    #[test]
+    fn pattern_round_trip() {
+        let pattern = vec![
+            Token::Ins(Begin),
+            Token::Push(Operand::Any),
+            Token::Push(Operand::Range(-5..10)),
+            Token::Push(Operand::Type(ValueType::Symbol)),
+            Token::Push(Operand::Capture("x".into())),
+            Token::Push(Operand::Capture("a b;\\".into())),
+            Token::Push(Operand::Exact(Symbol("y".into()))),
+            Token::Ins(If),
+            Token::AnyBlock,
+            Token::Ins(End),
+            Token::AnySequence,
+            Token::Ins(Push(Int(3))),
+            Token::Ins(End),
+        ];
+
+        let text = PatternListing(&pattern).to_string();
+
+        assert_eq!(
+            "begin\n  push _\n  push -5..10\n  push _:symbol\n  push ?x\n  push ?a\\u{20}b\\u{3b}\\u{5c}\n  push =$y\n  if\n    <block>\n  end\n  ...\n  push 3\nend\n",
+            text
+        );
+        assert_eq!(pattern, parse_pattern(&text).unwrap());
+    }
+
+    #[test]
+    fn pattern_parse_errors() {
+        assert_eq!(
+            Err(ParseErrorKind::InvalidOperand("?".into())),
+            parse_pattern("if push ? end").map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(ParseErrorKind::InvalidOperand("1..x".into())),
+            parse_pattern("if push 1..x end").map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(ParseErrorKind::InvalidOperand("_:float".into())),
+            parse_pattern("if push _:float end").map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(ParseErrorKind::InvalidOperand("=x".into())),
+            parse_pattern("if push =x end").map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(ParseErrorKind::InvalidOperand("?a\\u{zz}".into())),
+            parse_pattern("if push ?a\\u{zz} end").map_err(|err| err.kind)
+        );
+    }
+}
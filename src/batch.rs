@@ -0,0 +1,136 @@
+//!
+//! Matching of many programs against one registry in parallel. The registry is
+//! shared by all threads by reference, its index is never cloned.
+//!
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use crate::BlockInfo;
+use crate::BlockRegistry;
+use crate::Instruction;
+use crate::MatchError;
+
+/// The number of programs a thread takes at once.
+const CHUNK_LEN: usize = 64;
+
+impl BlockRegistry {
+    /// Finds matches of execution blocks inside each program, see
+    /// [`BlockRegistry::find_matches`]. Programs are matched on all available
+    /// cores, results are in the order of programs.
+    ///
+    /// # Arguments
+    ///
+    /// * programs - Programs for matching with the registry.
+    ///
+    pub fn find_matches_batch<P>(&self, programs: &[P]) -> Vec<Result<Vec<BlockInfo>, MatchError>>
+    where
+        P: AsRef<[Instruction]> + Sync,
+    {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        self.find_matches_batch_with_threads(programs, threads)
+    }
+
+    /// Finds matches of execution blocks inside each program like
+    /// [`BlockRegistry::find_matches_batch`], but with at most the specified
+    /// number of threads.
+    ///
+    /// # Arguments
+    ///
+    /// * programs - Programs for matching with the registry.
+    /// * threads - The greatest number of threads, 0 is the same as 1.
+    ///
+    pub fn find_matches_batch_with_threads<P>(
+        &self,
+        programs: &[P],
+        threads: usize,
+    ) -> Vec<Result<Vec<BlockInfo>, MatchError>>
+    where
+        P: AsRef<[Instruction]> + Sync,
+    {
+        let threads = threads.min(programs.len().div_ceil(CHUNK_LEN)).max(1);
+        if threads == 1 {
+            return programs
+                .iter()
+                .map(|program| self.find_matches(program.as_ref()))
+                .collect();
+        }
+
+        // threads take chunks of programs one by one, so a thread which got
+        // short programs doesn't wait for others
+        let next_chunk = AtomicUsize::new(0);
+        let mut chunks: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut matched = Vec::new();
+                        loop {
+                            let start = next_chunk.fetch_add(1, Ordering::Relaxed) * CHUNK_LEN;
+                            if start >= programs.len() {
+                                return matched;
+                            }
+                            let end = programs.len().min(start + CHUNK_LEN);
+                            let results = programs[start..end]
+                                .iter()
+                                .map(|program| self.find_matches(program.as_ref()))
+                                .collect::<Vec<_>>();
+                            matched.push((start, results));
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("matching thread panicked"))
+                .collect()
+        });
+
+        chunks.sort_unstable_by_key(|(start, _)| *start);
+        chunks
+            .into_iter()
+            .flat_map(|(_, results)| results)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BlockRegistry;
+    use crate::Instruction::*;
+    use crate::MatchError;
+    use crate::Value::*;
+
+    #[test]
+    fn batch_results_are_in_order() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(1)), End], &[Begin, End]]);
+        let programs: Vec<_> = (0..1000)
+            .map(|idx| match idx % 3 {
+                0 => vec![Begin, If, Push(Int(idx % 2)), End, End],
+                1 => vec![Begin, End],
+                _ => vec![],
+            })
+            .collect();
+
+        let sequential: Vec<_> = programs
+            .iter()
+            .map(|program| registry.find_matches(program))
+            .collect();
+        let parallel = registry.find_matches_batch_with_threads(&programs, 4);
+
+        assert_eq!(sequential, parallel);
+        assert_eq!(Err(MatchError::NoOneBlockFound), parallel[2]);
+    }
+
+    #[test]
+    fn batch_of_borrowed_programs() {
+        let registry = BlockRegistry::new(&[&[Begin, End]]);
+        let program = [Begin, End];
+
+        let results = registry.find_matches_batch(&[&program[..], &program[..]]);
+
+        assert_eq!(2, results.len());
+        assert_eq!(Some(0), results[1].as_ref().unwrap()[0].registry_idx());
+        assert!(registry.find_matches_batch::<Vec<_>>(&[]).is_empty());
+    }
+}
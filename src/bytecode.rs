@@ -0,0 +1,487 @@
+//!
+//! A compact binary encoding of programs.
+//!
+//! The bytecode starts with a header: 4 magic bytes `BMBC` and a version
+//! byte. The header is followed by instructions, each instruction is a single
+//! opcode byte. Operands of 'Push' are encoded by the opcode of their type: a
+//! boolean is a part of the opcode, an integer follows the opcode as a zigzag
+//! LEB128 varint and a symbol follows the opcode as its length in bytes, which
+//! is an unsigned LEB128 varint, and its UTF-8 bytes. The registry index of
+//! 'Call' follows the opcode as an unsigned LEB128 varint.
+//!
+//! Older versions of the bytecode are decoded as well. Version 1 has only the
+//! original instructions with integer operands, which are encoded as unsigned
+//! LEB128 varints, version 2 has no 'Call'. An opcode which is newer than the
+//! version of the bytecode is unknown.
+//!
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::Instruction;
+use crate::Value;
+
+/// Magic bytes at the beginning of the bytecode.
+pub const MAGIC: &[u8; 4] = b"BMBC";
+/// The current version of the bytecode format.
+pub const VERSION: u8 = 3;
+/// The version of the bytecode format without typed operands.
+const UNTYPED_VERSION: u8 = 1;
+/// The version of the bytecode format without 'Call'.
+const TYPED_VERSION: u8 = 2;
+
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+const PUSH_INT: u8 = 0x01;
+const OR: u8 = 0x02;
+const AND: u8 = 0x03;
+const NOT: u8 = 0x04;
+const IF: u8 = 0x05;
+const BEGIN: u8 = 0x06;
+const END: u8 = 0x07;
+const ELSE: u8 = 0x08;
+const LOOP: u8 = 0x09;
+const BREAK: u8 = 0x0A;
+const XOR: u8 = 0x0B;
+const DUP: u8 = 0x0C;
+const POP: u8 = 0x0D;
+const SWAP: u8 = 0x0E;
+const PUSH_FALSE: u8 = 0x0F;
+const PUSH_TRUE: u8 = 0x10;
+const PUSH_SYMBOL: u8 = 0x11;
+const CALL: u8 = 0x12;
+
+/// An error occurred while decoding a bytecode.
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecodeError {
+    /// An offset of the failed byte in the bytecode
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecodeErrorKind {
+    /// The bytecode doesn't start with the magic bytes.
+    BadMagic,
+    /// The bytecode version isn't supported.
+    UnsupportedVersion(u8),
+    /// The byte isn't a known opcode.
+    UnknownOpcode(u8),
+    /// The bytecode ends in the middle of the header or an instruction.
+    Truncated,
+    /// The operand doesn't fit into its type.
+    OperandOverflow,
+    /// The name of the symbol isn't a valid UTF-8 string.
+    InvalidSymbol,
+}
+
+/// Encodes the program into the bytecode.
+pub fn encode(program: &[Instruction]) -> Vec<u8> {
+    use crate::Instruction::*;
+
+    let mut bytecode = Vec::with_capacity(HEADER_LEN + program.len());
+    bytecode.extend_from_slice(MAGIC);
+    bytecode.push(VERSION);
+
+    for instruction in program {
+        match instruction {
+            Push(Value::Bool(false)) => bytecode.push(PUSH_FALSE),
+            Push(Value::Bool(true)) => bytecode.push(PUSH_TRUE),
+            Push(Value::Int(value)) => {
+                bytecode.push(PUSH_INT);
+                write_varint(&mut bytecode, ((value << 1) ^ (value >> 63)) as u64);
+            }
+            Push(Value::Symbol(name)) => {
+                bytecode.push(PUSH_SYMBOL);
+                write_varint(&mut bytecode, name.len() as u64);
+                bytecode.extend_from_slice(name.as_bytes());
+            }
+            Or => bytecode.push(OR),
+            And => bytecode.push(AND),
+            Not => bytecode.push(NOT),
+            If => bytecode.push(IF),
+            Begin => bytecode.push(BEGIN),
+            End => bytecode.push(END),
+            Else => bytecode.push(ELSE),
+            Loop => bytecode.push(LOOP),
+            Break => bytecode.push(BREAK),
+            Xor => bytecode.push(XOR),
+            Dup => bytecode.push(DUP),
+            Pop => bytecode.push(POP),
+            Swap => bytecode.push(SWAP),
+            Call(registry_idx) => {
+                bytecode.push(CALL);
+                write_varint(&mut bytecode, *registry_idx as u64);
+            }
+        }
+    }
+
+    bytecode
+}
+
+/// Decodes the whole program from the bytecode.
+pub fn decode(bytecode: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    Decoder::new(bytecode)?.collect()
+}
+
+/// Decodes instructions from a borrowed bytecode one by one.
+#[derive(Debug, Clone)]
+pub struct Decoder<'a> {
+    bytecode: &'a [u8],
+    /// The version of the bytecode format
+    version: u8,
+    /// An offset of the next instruction
+    offset: usize,
+    /// Whether an error has been already returned
+    failed: bool,
+}
+
+impl<'a> Decoder<'a> {
+    /// Checks the header of the bytecode and creates the decoder of its
+    /// instructions.
+    pub fn new(bytecode: &'a [u8]) -> Result<Self, DecodeError> {
+        let error = |offset, kind| Err(DecodeError { offset, kind });
+
+        for (offset, magic) in MAGIC.iter().enumerate() {
+            match bytecode.get(offset) {
+                Some(byte) if byte == magic => {}
+                Some(_) => return error(offset, DecodeErrorKind::BadMagic),
+                None => return error(offset, DecodeErrorKind::Truncated),
+            }
+        }
+        let version = match bytecode.get(MAGIC.len()) {
+            Some(&version) if (UNTYPED_VERSION..=VERSION).contains(&version) => version,
+            Some(&version) => {
+                return error(MAGIC.len(), DecodeErrorKind::UnsupportedVersion(version))
+            }
+            None => return error(MAGIC.len(), DecodeErrorKind::Truncated),
+        };
+
+        Ok(Decoder {
+            bytecode,
+            version,
+            offset: HEADER_LEN,
+            failed: false,
+        })
+    }
+
+    fn decode_next(&mut self) -> Result<Instruction, DecodeError> {
+        use crate::Instruction::*;
+
+        let opcode_offset = self.offset;
+        let opcode = self.bytecode[opcode_offset];
+        self.offset += 1;
+        let unknown = Err(DecodeError {
+            offset: opcode_offset,
+            kind: DecodeErrorKind::UnknownOpcode(opcode),
+        });
+        if introduced_in(opcode) > self.version {
+            return unknown;
+        }
+
+        let instruction = match opcode {
+            PUSH_INT => Push(Value::Int(self.read_int()?)),
+            PUSH_FALSE => Push(Value::Bool(false)),
+            PUSH_TRUE => Push(Value::Bool(true)),
+            PUSH_SYMBOL => Push(Value::Symbol(self.read_symbol()?)),
+            OR => Or,
+            AND => And,
+            NOT => Not,
+            IF => If,
+            BEGIN => Begin,
+            END => End,
+            ELSE => Else,
+            LOOP => Loop,
+            BREAK => Break,
+            XOR => Xor,
+            DUP => Dup,
+            POP => Pop,
+            SWAP => Swap,
+            CALL => Call(self.read_index()?),
+            _ => return unknown,
+        };
+
+        Ok(instruction)
+    }
+
+    /// Reads an integer operand, it is a zigzag LEB128 varint, but an unsigned
+    /// one in the untyped version.
+    fn read_int(&mut self) -> Result<i64, DecodeError> {
+        let offset = self.offset;
+        let value = self.read_varint()?;
+        if self.version >= TYPED_VERSION {
+            return Ok((value >> 1) as i64 ^ -((value & 1) as i64));
+        }
+        i64::try_from(value).map_err(|_| DecodeError {
+            offset,
+            kind: DecodeErrorKind::OperandOverflow,
+        })
+    }
+
+    /// Reads a symbol operand, its length and then its bytes.
+    fn read_symbol(&mut self) -> Result<String, DecodeError> {
+        let offset = self.offset;
+        let len = self.read_varint()?;
+        let start = self.offset;
+        let bytes = usize::try_from(len)
+            .ok()
+            .and_then(|len| self.bytecode.get(start..start.checked_add(len)?))
+            .ok_or(DecodeError {
+                offset: self.bytecode.len(),
+                kind: DecodeErrorKind::Truncated,
+            })?;
+        let name = String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError {
+            offset,
+            kind: DecodeErrorKind::InvalidSymbol,
+        })?;
+        self.offset += bytes.len();
+        Ok(name)
+    }
+
+    /// Reads a registry index operand, it is an unsigned LEB128 varint.
+    fn read_index(&mut self) -> Result<usize, DecodeError> {
+        let offset = self.offset;
+        let value = self.read_varint()?;
+        usize::try_from(value).map_err(|_| DecodeError {
+            offset,
+            kind: DecodeErrorKind::OperandOverflow,
+        })
+    }
+
+    /// Reads an unsigned LEB128 varint.
+    fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        let mut shift = 0u32;
+
+        loop {
+            let byte = *self.bytecode.get(self.offset).ok_or(DecodeError {
+                offset: self.offset,
+                kind: DecodeErrorKind::Truncated,
+            })?;
+            let bits = (byte & 0x7F) as u64;
+            if shift >= u64::BITS || (bits << shift) >> shift != bits {
+                return Err(DecodeError {
+                    offset: self.offset,
+                    kind: DecodeErrorKind::OperandOverflow,
+                });
+            }
+            value |= bits << shift;
+            shift += 7;
+            self.offset += 1;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = Result<Instruction, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.bytecode.len() {
+            return None;
+        }
+        let result = self.decode_next();
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// Returns the version of the bytecode format which introduced the opcode.
+fn introduced_in(opcode: u8) -> u8 {
+    match opcode {
+        PUSH_INT..=END => UNTYPED_VERSION,
+        ELSE..=PUSH_SYMBOL => TYPED_VERSION,
+        _ => VERSION,
+    }
+}
+
+/// Writes the value as an unsigned LEB128 varint.
+fn write_varint(bytecode: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytecode.push(byte);
+            return;
+        }
+        bytecode.push(byte | 0x80);
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Decode error at the offset {}: ", self.offset)?;
+        match self.kind {
+            DecodeErrorKind::BadMagic => write!(f, "bad magic bytes"),
+            DecodeErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {}", version)
+            }
+            DecodeErrorKind::UnknownOpcode(opcode) => write!(f, "unknown opcode {:#04x}", opcode),
+            DecodeErrorKind::Truncated => write!(f, "unexpected end of bytecode"),
+            DecodeErrorKind::OperandOverflow => write!(f, "operand overflow"),
+            DecodeErrorKind::InvalidSymbol => write!(f, "invalid symbol"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::decode;
+    use crate::bytecode::encode;
+    use crate::bytecode::DecodeError;
+    use crate::bytecode::DecodeErrorKind;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn encode_and_decode_round_trip() {
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(0)),
+            Push(Int(-1)),
+            Push(Int(64)),
+            Push(Int(i64::MIN)),
+            Push(Int(i64::MAX)),
+            Push(Bool(true)),
+            Push(Bool(false)),
+            Push(Symbol("x".into())),
+            Push(Symbol(String::new())),
+            Or,
+            And,
+            Not,
+            Else,
+            Loop,
+            Xor,
+            Dup,
+            Pop,
+            Swap,
+            Call(0),
+            Call(300),
+            Break,
+            End,
+            End,
+            End,
+        ];
+
+        let bytecode = encode(&program);
+
+        assert_eq!(
+            b"BMBC\x03\x06\x05\x01\x00\x01\x01\x01\x80\x01",
+            &bytecode[..14]
+        );
+        assert_eq!(program, decode(&bytecode).unwrap());
+        assert_eq!(Ok(vec![]), decode(&encode(&[])));
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert_eq!(
+            Err(DecodeError {
+                offset: 1,
+                kind: DecodeErrorKind::BadMagic,
+            }),
+            decode(b"BXBC\x01")
+        );
+        assert_eq!(
+            Err(DecodeError {
+                offset: 4,
+                kind: DecodeErrorKind::UnsupportedVersion(9),
+            }),
+            decode(b"BMBC\x09")
+        );
+        assert_eq!(
+            Err(DecodeError {
+                offset: 2,
+                kind: DecodeErrorKind::Truncated,
+            }),
+            decode(b"BM")
+        );
+    }
+
+    #[test]
+    fn invalid_instructions() {
+        assert_eq!(
+            "Decode error at the offset 6: unknown opcode 0x2a",
+            decode(b"BMBC\x01\x06\x2A\x07").unwrap_err().to_string()
+        );
+        assert_eq!(
+            Err(DecodeError {
+                offset: 8,
+                kind: DecodeErrorKind::Truncated,
+            }),
+            decode(b"BMBC\x01\x06\x01\x80")
+        );
+        let mut overflow = b"BMBC\x01\x01".to_vec();
+        overflow.extend_from_slice(&[0xFF; 10]);
+        overflow.push(0x01);
+        assert_eq!(
+            Err(DecodeErrorKind::OperandOverflow),
+            decode(&overflow).map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(DecodeError {
+                offset: 6,
+                kind: DecodeErrorKind::InvalidSymbol,
+            }),
+            decode(b"BMBC\x03\x11\x01\xFF")
+        );
+        assert_eq!(
+            Err(DecodeError {
+                offset: 9,
+                kind: DecodeErrorKind::Truncated,
+            }),
+            decode(b"BMBC\x03\x11\x05ab")
+        );
+    }
+
+    #[test]
+    fn opcodes_of_newer_versions() {
+        assert_eq!(
+            Ok(vec![If, Push(Bool(true)), Else, Push(Int(-1)), End]),
+            decode(b"BMBC\x02\x05\x10\x08\x01\x01\x07")
+        );
+        assert_eq!(
+            Err(DecodeError {
+                offset: 6,
+                kind: DecodeErrorKind::UnknownOpcode(0x12),
+            }),
+            decode(b"BMBC\x02\x06\x12\x00\x07")
+        );
+        for opcode in 0x08..=0x12 {
+            assert_eq!(
+                Err(DecodeErrorKind::UnknownOpcode(opcode)),
+                decode(&[b'B', b'M', b'B', b'C', 1, opcode, 0]).map_err(|err| err.kind)
+            );
+        }
+    }
+
+    #[test]
+    fn untyped_version() {
+        assert_eq!(
+            Ok(vec![Begin, Push(Int(128)), End]),
+            decode(b"BMBC\x01\x06\x01\x80\x01\x07")
+        );
+        assert_eq!(
+            Err(DecodeErrorKind::UnknownOpcode(0x10)),
+            decode(b"BMBC\x01\x10").map_err(|err| err.kind)
+        );
+        let mut overflow = b"BMBC\x01\x01".to_vec();
+        overflow.extend_from_slice(&[0xFF; 9]);
+        overflow.push(0x01);
+        assert_eq!(
+            Err(DecodeErrorKind::OperandOverflow),
+            decode(&overflow).map_err(|err| err.kind)
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//!
+//! Canonicalization of blocks. Instructions between block delimiters are
+//! evaluated symbolically into boolean expressions, which are then rewritten
+//! to a canonical form, so blocks with the same meaning have the same
+//! canonical form even if their instructions differ.
+//!
+//! The operand of 'Push' is treated as a constant: a boolean is a constant
+//! of the expression and any other value is an opaque atom.
+//!
+
+use crate::Instruction;
+use crate::Value;
+
+/// A single item of the canonical form of a block.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Canonical {
+    /// An instruction which can't be evaluated symbolically, e.g. a delimiter.
+    Ins(Instruction),
+    /// A sequence of instructions evaluated symbolically.
+    Segment(Segment),
+}
+
+/// A canonical form of a sequence of boolean instructions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Segment {
+    /// A number of values taken from the stack before the segment
+    consumed: usize,
+    /// Values left on the stack by the segment, the top one is the last
+    outputs: Vec<Expr>,
+}
+
+/// A boolean expression in the negation normal form.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Expr {
+    Const(bool),
+    /// A 'Push' operand which isn't a boolean
+    Atom(Value),
+    /// A value taken from the stack before the segment, 0 is the top one
+    Input(usize),
+    /// A negation, applied to atoms and inputs only
+    Not(Box<Expr>),
+    /// A conjunction of at least two sorted distinct operands
+    And(Vec<Expr>),
+    /// A disjunction of at least two sorted distinct operands
+    Or(Vec<Expr>),
+}
+
+/// Returns true if the instruction can be evaluated symbolically.
+pub(crate) fn is_boolean(instruction: &Instruction) -> bool {
+    use crate::Instruction::*;
+
+    match instruction {
+        Push(_) | Or | And | Not | Xor | Dup | Pop | Swap => true,
+        If | Begin | End | Else | Loop | Break | Call(_) => false,
+    }
+}
+
+/// Evaluates the sequence of boolean instructions symbolically and returns
+/// its canonical form.
+///
+/// # Panics
+///
+/// If any instruction can't be evaluated symbolically, see [`is_boolean`].
+pub(crate) fn canonicalize_segment(segment: &[Instruction]) -> Segment {
+    use crate::Instruction::*;
+
+    let mut stack = Vec::new();
+    let mut consumed = 0;
+    let mut pop = |stack: &mut Vec<Expr>| {
+        stack.pop().unwrap_or_else(|| {
+            consumed += 1;
+            Expr::Input(consumed - 1)
+        })
+    };
+
+    for instruction in segment {
+        match instruction {
+            Push(Value::Bool(value)) => stack.push(Expr::Const(*value)),
+            Push(value) => stack.push(Expr::Atom(value.clone())),
+            Not => {
+                let operand = pop(&mut stack);
+                stack.push(not(operand));
+            }
+            And | Or => {
+                let operands = vec![pop(&mut stack), pop(&mut stack)];
+                stack.push(junction(operands, *instruction == And));
+            }
+            Xor => {
+                let right = pop(&mut stack);
+                let left = pop(&mut stack);
+                let only_left = junction(vec![left.clone(), not(right.clone())], true);
+                let only_right = junction(vec![not(left), right], true);
+                stack.push(junction(vec![only_left, only_right], false));
+            }
+            Dup => {
+                let top = pop(&mut stack);
+                stack.push(top.clone());
+                stack.push(top);
+            }
+            Pop => {
+                pop(&mut stack);
+            }
+            Swap => {
+                let top = pop(&mut stack);
+                let below = pop(&mut stack);
+                stack.push(top);
+                stack.push(below);
+            }
+            If | Begin | End | Else | Loop | Break | Call(_) => {
+                panic!("{:?} can't be evaluated symbolically", instruction)
+            }
+        }
+    }
+
+    Segment {
+        consumed,
+        outputs: stack,
+    }
+}
+
+/// Returns the canonical form of the block, i.e. its delimiters as is and
+/// all boolean instructions between them canonicalized.
+pub(crate) fn canonical_form(block: &[Instruction]) -> Vec<Canonical> {
+    let mut form = Vec::new();
+    let mut segment_start = 0;
+
+    for (idx, instruction) in block.iter().enumerate() {
+        if !is_boolean(instruction) {
+            if segment_start < idx {
+                let segment = canonicalize_segment(&block[segment_start..idx]);
+                form.push(Canonical::Segment(segment));
+            }
+            form.push(Canonical::Ins(instruction.clone()));
+            segment_start = idx + 1;
+        }
+    }
+    if segment_start < block.len() {
+        let segment = canonicalize_segment(&block[segment_start..]);
+        form.push(Canonical::Segment(segment));
+    }
+
+    form
+}
+
+/// Negates the expression, negations are pushed down to atoms and inputs by
+/// De Morgan's laws.
+fn not(expr: Expr) -> Expr {
+    match expr {
+        Expr::Const(value) => Expr::Const(!value),
+        Expr::Not(inner) => *inner,
+        Expr::And(operands) => junction(operands.into_iter().map(not).collect(), false),
+        Expr::Or(operands) => junction(operands.into_iter().map(not).collect(), true),
+        atom => Expr::Not(Box::new(atom)),
+    }
+}
+
+/// Returns a conjunction (if `is_and`) or a disjunction of canonical
+/// operands. Nested junctions of the same kind are flattened, constants are
+/// folded, operands are sorted and deduplicated.
+fn junction(operands: Vec<Expr>, is_and: bool) -> Expr {
+    let mut flat = Vec::with_capacity(operands.len());
+
+    for operand in operands {
+        match operand {
+            Expr::And(inner) if is_and => flat.extend(inner),
+            Expr::Or(inner) if !is_and => flat.extend(inner),
+            Expr::Const(value) if value == is_and => {} // an identity element
+            Expr::Const(value) => return Expr::Const(value),
+            other => flat.push(other),
+        }
+    }
+    flat.sort();
+    flat.dedup();
+
+    match flat.len() {
+        0 => Expr::Const(is_and),
+        1 => flat.remove(0),
+        _ if is_and => Expr::And(flat),
+        _ => Expr::Or(flat),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::canonical::canonical_form;
+    use crate::canonical::canonicalize_segment;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn commutative_operands() {
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), Or]),
+            canonicalize_segment(&[Push(Int(3)), Push(Int(2)), Or])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), And, Push(Int(4)), And]),
+            canonicalize_segment(&[Push(Int(4)), Push(Int(2)), Push(Int(3)), And, And])
+        );
+        assert_ne!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), Or]),
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), And])
+        );
+    }
+
+    #[test]
+    fn negations() {
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2))]),
+            canonicalize_segment(&[Push(Int(2)), Not, Not])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), And, Not]),
+            canonicalize_segment(&[Push(Int(2)), Not, Push(Int(3)), Not, Or])
+        );
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(
+            canonicalize_segment(&[Push(Bool(false))]),
+            canonicalize_segment(&[Push(Int(2)), Push(Bool(false)), And])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2))]),
+            canonicalize_segment(&[Push(Bool(true)), Push(Int(2)), And])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Bool(true))]),
+            canonicalize_segment(&[Push(Bool(false)), Not])
+        );
+        assert_ne!(
+            canonicalize_segment(&[Push(Int(2))]),
+            canonicalize_segment(&[Push(Int(1)), Push(Int(2)), And])
+        );
+    }
+
+    #[test]
+    fn stack_operations() {
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), Or]),
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), Swap, Or])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2))]),
+            canonicalize_segment(&[Push(Int(2)), Dup, Dup, Pop, And])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), Xor]),
+            canonicalize_segment(&[Push(Int(3)), Push(Int(2)), Xor])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Push(Int(3)), Xor]),
+            canonicalize_segment(&[
+                Push(Int(2)),
+                Push(Int(3)),
+                Not,
+                And,
+                Push(Int(2)),
+                Not,
+                Push(Int(3)),
+                And,
+                Or
+            ])
+        );
+        assert_eq!(
+            canonicalize_segment(&[Push(Int(2)), Not]),
+            canonicalize_segment(&[Push(Int(2)), Push(Bool(true)), Xor])
+        );
+    }
+
+    #[test]
+    fn values_from_previous_segments() {
+        assert_eq!(
+            canonical_form(&[Begin, Push(Int(2)), If, Push(Int(3)), Or, End, End]),
+            canonical_form(&[
+                Begin,
+                Push(Int(2)),
+                If,
+                Push(Int(3)),
+                Not,
+                Not,
+                Or,
+                End,
+                End
+            ])
+        );
+        assert_eq!(
+            canonical_form(&[If, Push(Int(3)), Or, End]),
+            canonical_form(&[If, Push(Int(3)), Or, Not, Not, End])
+        );
+        assert_ne!(
+            canonical_form(&[If, Push(Int(3)), Or, End]),
+            canonical_form(&[If, Push(Int(3)), Push(Int(3)), Or, End])
+        );
+    }
+}
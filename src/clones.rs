@@ -0,0 +1,205 @@
+//!
+//! Detection of repeated blocks within a program or across many programs,
+//! without any registry. Blocks are grouped by the same fingerprints which
+//! the registry uses for lookups, so the programs are walked once.
+//!
+
+use std::collections::HashMap;
+
+use crate::canonical;
+use crate::fingerprint::BlockWalker;
+use crate::fingerprint::Fingerprint;
+use crate::fingerprint::Step;
+use crate::Instruction;
+use crate::MatchError;
+
+/// A group of blocks which are equal to each other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    /// Instructions of the first block of the group
+    pub block: Vec<Instruction>,
+    /// Positions of all blocks of the group, in the order of programs and
+    /// then in the order of 'End' instructions
+    pub positions: Vec<BlockPosition>,
+}
+
+/// A position of a block in one of the programs.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Hash)]
+pub struct BlockPosition {
+    /// An index of the program
+    pub program_idx: usize,
+    /// An index of the first block instruction in the program
+    pub start: usize,
+    /// An index of the last ('End') block instruction in the program
+    pub end: usize,
+}
+
+impl DuplicateGroup {
+    /// Returns the number of blocks in the group.
+    pub fn count(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+/// Finds blocks which occur more than once in the programs, nested blocks are
+/// taken into account as well. Groups are in the order of their first blocks.
+///
+/// Fails on the first program with unbalanced blocks along with its index.
+pub fn find_duplicate_blocks<P: AsRef<[Instruction]>>(
+    programs: &[P],
+) -> Result<Vec<DuplicateGroup>, (usize, MatchError)> {
+    group_blocks(programs, false)
+}
+
+/// Finds blocks which have the same meaning and occur more than once in the
+/// programs, see [`BlockRegistry::with_canonical_matching`] for the meaning
+/// of blocks. Otherwise the same as [`find_duplicate_blocks`].
+///
+/// [`BlockRegistry::with_canonical_matching`]: crate::BlockRegistry::with_canonical_matching
+pub fn find_equivalent_blocks<P: AsRef<[Instruction]>>(
+    programs: &[P],
+) -> Result<Vec<DuplicateGroup>, (usize, MatchError)> {
+    group_blocks(programs, true)
+}
+
+fn group_blocks<P: AsRef<[Instruction]>>(
+    programs: &[P],
+    by_meaning: bool,
+) -> Result<Vec<DuplicateGroup>, (usize, MatchError)> {
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    // indices of groups by fingerprints of their blocks
+    let mut index: HashMap<Fingerprint, Vec<usize>> = HashMap::new();
+
+    for (program_idx, program) in programs.iter().enumerate() {
+        let program = program.as_ref();
+        let mut walker = BlockWalker::default();
+        if by_meaning {
+            walker = walker.with_canonical();
+        }
+
+        for (ins_idx, instruction) in program.iter().enumerate() {
+            let step = walker.step(ins_idx, instruction);
+            if step == Step::UnmatchedEnd {
+                let err = MatchError::UnmatchedEnd { position: ins_idx };
+                return Err((program_idx, err));
+            }
+            for closed in step.into_closed() {
+                let block = closed.slice(program);
+                let fingerprint = closed.canonical.unwrap_or(closed.fingerprint);
+                let candidates = index.entry(fingerprint).or_default();
+                // the canonical form is needed only to tell apart blocks
+                // with the same fingerprint
+                let form = if by_meaning && !candidates.is_empty() {
+                    Some(canonical::canonical_form(block))
+                } else {
+                    None
+                };
+                let found = candidates.iter().copied().find(|&group_idx| {
+                    let known = &groups[group_idx].block;
+                    match &form {
+                        Some(form) => canonical::canonical_form(known) == *form,
+                        None => known.as_slice() == block,
+                    }
+                });
+                let position = BlockPosition {
+                    program_idx,
+                    start: closed.start,
+                    end: closed.end,
+                };
+                match found {
+                    Some(group_idx) => groups[group_idx].positions.push(position),
+                    None => {
+                        candidates.push(groups.len());
+                        groups.push(DuplicateGroup {
+                            block: block.to_vec(),
+                            positions: vec![position],
+                        });
+                    }
+                }
+            }
+        }
+
+        let open_blocks = walker.open_blocks();
+        if !open_blocks.is_empty() {
+            let err = MatchError::UnclosedBlocks {
+                starts: open_blocks,
+            };
+            return Err((program_idx, err));
+        }
+    }
+
+    groups.retain(|group| group.count() > 1);
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clones::find_duplicate_blocks;
+    use crate::clones::find_equivalent_blocks;
+    use crate::clones::BlockPosition;
+    use crate::clones::DuplicateGroup;
+    use crate::Instruction::*;
+    use crate::MatchError;
+    use crate::Value::*;
+
+    #[test]
+    fn duplicates_within_and_across_programs() {
+        let programs = vec![
+            vec![Begin, If, Push(Int(1)), End, If, Push(Int(1)), End, End],
+            vec![If, Push(Int(2)), End, Begin, If, Push(Int(1)), End, End],
+        ];
+
+        let groups = find_duplicate_blocks(&programs).unwrap();
+
+        assert_eq!(
+            vec![DuplicateGroup {
+                block: vec![If, Push(Int(1)), End],
+                positions: vec![position(0, 1, 3), position(0, 4, 6), position(1, 4, 6)],
+            }],
+            groups
+        );
+        assert_eq!(3, groups[0].count());
+    }
+
+    #[test]
+    fn equivalent_blocks() {
+        let programs = vec![
+            vec![Begin, Push(Int(2)), Push(Int(3)), Or, End],
+            vec![Begin, Push(Int(3)), Push(Int(2)), Or, End],
+        ];
+
+        let duplicates = find_duplicate_blocks(&programs).unwrap();
+        let equivalents = find_equivalent_blocks(&programs).unwrap();
+
+        assert!(duplicates.is_empty());
+        assert_eq!(1, equivalents.len());
+        assert_eq!(
+            vec![Begin, Push(Int(2)), Push(Int(3)), Or, End],
+            equivalents[0].block
+        );
+        assert_eq!(
+            vec![position(0, 0, 4), position(1, 0, 4)],
+            equivalents[0].positions
+        );
+    }
+
+    #[test]
+    fn unbalanced_program() {
+        let programs = vec![vec![Begin, End], vec![Begin]];
+
+        let result = find_duplicate_blocks(&programs);
+
+        assert_eq!(
+            Err((1, MatchError::UnclosedBlocks { starts: vec![0] })),
+            result
+        );
+    }
+
+    fn position(program_idx: usize, start: usize, end: usize) -> BlockPosition {
+        BlockPosition {
+            program_idx,
+            start,
+            end,
+        }
+    }
+}
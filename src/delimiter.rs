@@ -0,0 +1,59 @@
+//!
+//! Block delimiters of instruction sets. Matching of blocks only needs to know
+//! which instructions open and close blocks, so any instruction type can be
+//! matched once it implements [`BlockDelimiter`].
+//!
+
+use crate::Instruction;
+
+/// A role of an instruction in the block structure of a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Delimiter {
+    /// The instruction opens a new block.
+    Open,
+    /// The instruction closes the innermost block.
+    Close,
+    /// The instruction closes the innermost block and opens a new one right
+    /// away, the instruction is the last one of the closed block and the first
+    /// one of the opened block.
+    Split,
+    /// The instruction neither opens nor closes a block.
+    Neutral,
+}
+
+/// Describes the block structure of an instruction set.
+pub trait BlockDelimiter {
+    /// Returns the role of this instruction in the block structure.
+    fn delimiter(&self) -> Delimiter;
+
+    /// Returns true if this closing or splitting instruction can close a block
+    /// opened by the specified instruction. By default any closing instruction
+    /// closes any block. A closing instruction which can't close the innermost
+    /// block is treated as a stray one.
+    fn closes(&self, _opener: &Self) -> bool {
+        true
+    }
+}
+
+impl BlockDelimiter for Instruction {
+    fn delimiter(&self) -> Delimiter {
+        use crate::Instruction::*;
+
+        match self {
+            Begin | If | Loop => Delimiter::Open,
+            End => Delimiter::Close,
+            Else => Delimiter::Split,
+            Push(_) | Or | And | Not | Break | Xor | Dup | Pop | Swap | Call(_) => {
+                Delimiter::Neutral
+            }
+        }
+    }
+
+    /// 'Else' closes only an 'If' block, 'End' closes any block.
+    fn closes(&self, opener: &Self) -> bool {
+        match self {
+            Instruction::Else => *opener == Instruction::If,
+            _ => true,
+        }
+    }
+}
@@ -0,0 +1,692 @@
+//!
+//! Merkle-style block fingerprints. A fingerprint of a block is computed from
+//! its own instructions and fingerprints of its nested blocks, so all blocks of
+//! a program are fingerprinted in a single linear pass.
+//!
+
+use crate::canonical;
+use crate::canonical::Canonical;
+use crate::delimiter::BlockDelimiter;
+use crate::delimiter::Delimiter;
+use crate::Instruction;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::mem;
+
+/// A hash of a block, equal blocks always have equal fingerprints.
+pub(crate) type Fingerprint = u64;
+
+/// Marks an instruction of the block itself in the hasher input.
+const INSTRUCTION_TAG: u8 = 0;
+/// Marks a fingerprint of a nested block in the hasher input.
+const CHILD_TAG: u8 = 1;
+
+/// Walks through a program of any instruction type instruction by instruction
+/// and computes fingerprints of blocks as they are closed. Blocks are
+/// delimited as [`BlockDelimiter`] tells.
+pub(crate) struct DelimiterWalker<T> {
+    /// Currently opened blocks, the innermost block is the last one
+    stack: Vec<Frame<T>>,
+    /// Whether shape fingerprints should be computed as well
+    with_shapes: bool,
+}
+
+/// Walks through a program instruction by instruction and computes fingerprints
+/// of blocks as they are closed.
+#[derive(Default)]
+pub(crate) struct BlockWalker {
+    walker: DelimiterWalker<Instruction>,
+    /// Canonical forms of currently opened blocks, the innermost block is the
+    /// last one, None if canonical fingerprints aren't requested
+    canonical: Option<Vec<CanonicalFrame>>,
+}
+
+/// A block which is opened, but not closed yet.
+struct Frame<T> {
+    /// An index of the first block instruction
+    start: usize,
+    /// The instruction which opened the block
+    opener: T,
+    /// Accumulates instructions and nested block fingerprints of the block
+    hasher: DefaultHasher,
+    /// Accumulates the same as `hasher`, but ignores instruction operands
+    shape_hasher: Option<DefaultHasher>,
+    /// The whole split block with its previous arms hashed, if the block is
+    /// not the first arm of a split block
+    whole: Option<Box<Frame<()>>>,
+}
+
+/// A canonical form of a block which is opened, but not closed yet.
+#[derive(Default)]
+struct CanonicalFrame {
+    hasher: DefaultHasher,
+    /// Boolean instructions since the last delimiter, they are canonicalized
+    /// all together at the next delimiter
+    segment: Vec<Instruction>,
+    /// The canonical form of the whole split block with its previous arms
+    /// hashed, if the block is not the first arm of a split block
+    whole: Option<Box<CanonicalFrame>>,
+}
+
+/// A result of feeding a single instruction to the [`BlockWalker`].
+#[derive(Debug, PartialEq)]
+pub(crate) enum Step {
+    /// The instruction opened a new block.
+    Opened,
+    /// The instruction closed the block.
+    Closed(ClosedBlock),
+    /// The instruction closed the block and opened a new one, like 'Else'.
+    Split(ClosedBlock),
+    /// The instruction closed the last arm of a split block and the whole
+    /// split block along with it, like 'End' after 'Else'.
+    Joined {
+        arm: ClosedBlock,
+        whole: ClosedBlock,
+    },
+    /// The instruction closes a block, but there is no opened block or the
+    /// innermost block can't be closed by it.
+    UnmatchedEnd,
+    /// The instruction doesn't open or close any block.
+    Inner,
+}
+
+/// A block which was just closed by the [`BlockWalker`].
+#[derive(Debug, PartialEq)]
+pub(crate) struct ClosedBlock {
+    /// An index of the first block instruction
+    pub start: usize,
+    /// An index of the last ('End' or 'Else') block instruction
+    pub end: usize,
+    /// The number of blocks this block is nested into
+    pub depth: usize,
+    pub fingerprint: Fingerprint,
+    /// A fingerprint which ignores operands, if shapes were requested
+    pub shape: Option<Fingerprint>,
+    /// A fingerprint of the canonical form, if it was requested
+    pub canonical: Option<Fingerprint>,
+    /// Whether the block is an arm of a split block, e.g. 'If…Else'
+    pub is_arm: bool,
+    /// Whether the block is a whole split block, e.g. 'If…Else…End'
+    pub is_split: bool,
+}
+
+impl Step {
+    /// Returns blocks closed by the step in the order they were closed.
+    pub fn into_closed(self) -> impl Iterator<Item = ClosedBlock> {
+        let (first, second) = match self {
+            Step::Closed(closed) | Step::Split(closed) => (Some(closed), None),
+            Step::Joined { arm, whole } => (Some(arm), Some(whole)),
+            Step::Opened | Step::UnmatchedEnd | Step::Inner => (None, None),
+        };
+        first.into_iter().chain(second)
+    }
+}
+
+impl ClosedBlock {
+    /// Borrows instructions of this block from the program.
+    pub fn slice<'a, T>(&self, program: &'a [T]) -> &'a [T] {
+        &program[self.start..=self.end]
+    }
+}
+
+impl<T> Default for DelimiterWalker<T> {
+    fn default() -> Self {
+        DelimiterWalker {
+            stack: Vec::new(),
+            with_shapes: false,
+        }
+    }
+}
+
+impl<T: BlockDelimiter + Hash + Clone> DelimiterWalker<T> {
+    /// Makes the walker compute shape fingerprints as well, i.e. fingerprints
+    /// which depend on enum variants of instructions, but not on their fields.
+    pub fn with_shapes(mut self) -> Self {
+        self.with_shapes = true;
+        self
+    }
+
+    /// Feeds the next instruction of a program to the walker.
+    ///
+    /// # Arguments
+    ///
+    /// * ins_idx - An index of the instruction in the program.
+    /// * instruction - The instruction itself.
+    ///
+    pub fn step(&mut self, ins_idx: usize, instruction: &T) -> Step {
+        match instruction.delimiter() {
+            Delimiter::Open => {
+                self.open(ins_idx, instruction);
+                Step::Opened
+            }
+            Delimiter::Close => match self.close(ins_idx, instruction) {
+                Some((closed, None)) => {
+                    self.hash_into_parent(&closed);
+                    Step::Closed(closed)
+                }
+                Some((mut arm, Some(mut whole))) => {
+                    arm.is_arm = true;
+                    whole.hash_child(arm.fingerprint, arm.shape);
+                    let mut whole = whole.finish(ins_idx, arm.depth);
+                    whole.is_split = true;
+                    self.hash_into_parent(&whole);
+                    Step::Joined { arm, whole }
+                }
+                None => Step::UnmatchedEnd,
+            },
+            Delimiter::Split => match self.close(ins_idx, instruction) {
+                Some((mut arm, whole)) => {
+                    arm.is_arm = true;
+                    // the arm is hashed into the whole split block, which is
+                    // hashed into the parent once its last arm is closed
+                    let mut whole = whole
+                        .unwrap_or_else(|| Box::new(Frame::new(arm.start, (), self.with_shapes)));
+                    whole.hash_child(arm.fingerprint, arm.shape);
+                    self.open(ins_idx, instruction);
+                    if let Some(frame) = self.stack.last_mut() {
+                        frame.whole = Some(whole);
+                    }
+                    Step::Split(arm)
+                }
+                None => Step::UnmatchedEnd,
+            },
+            Delimiter::Neutral => {
+                if let Some(frame) = self.stack.last_mut() {
+                    frame.hash_instruction(instruction);
+                }
+                Step::Inner
+            }
+        }
+    }
+
+    /// Feeds the first instruction of a block to the walker, unlike
+    /// [`DelimiterWalker::step`] an instruction which splits blocks only opens
+    /// a new block here, so the second part of a split block can be walked on
+    /// its own.
+    pub fn start_block(&mut self, ins_idx: usize, instruction: &T) -> Step {
+        match instruction.delimiter() {
+            Delimiter::Split => {
+                self.open(ins_idx, instruction);
+                Step::Opened
+            }
+            _ => self.step(ins_idx, instruction),
+        }
+    }
+
+    /// Feeds the block which is nested into the innermost opened block, but
+    /// was closed by another walker. Only fingerprints of the block are hashed
+    /// into its parent, its instructions aren't walked again.
+    pub fn step_over(&mut self, closed: &ClosedBlock) {
+        if let Some(frame) = self.stack.last_mut() {
+            frame.hash_child(closed.fingerprint, closed.shape);
+        }
+    }
+
+    /// Returns start positions of all blocks which are opened, but not closed yet.
+    pub fn open_blocks(&self) -> Vec<usize> {
+        self.stack.iter().map(|frame| frame.start).collect()
+    }
+
+    fn open(&mut self, ins_idx: usize, instruction: &T) {
+        let mut frame = Frame::new(ins_idx, instruction.clone(), self.with_shapes);
+        frame.hash_instruction(instruction);
+        self.stack.push(frame);
+    }
+
+    /// Closes the innermost block without hashing it into its parent, returns
+    /// None if there is no opened block or the innermost block can't be closed
+    /// by the instruction. The whole split block is returned along with its
+    /// arm, if the block is not the first arm.
+    fn close(
+        &mut self,
+        ins_idx: usize,
+        instruction: &T,
+    ) -> Option<(ClosedBlock, Option<Box<Frame<()>>>)> {
+        match self.stack.last() {
+            Some(frame) if instruction.closes(&frame.opener) => {}
+            _ => return None,
+        }
+        let mut frame = self.stack.pop().expect("the innermost block is checked");
+        frame.hash_instruction(instruction);
+        let whole = frame.whole.take();
+        Some((frame.finish(ins_idx, self.stack.len()), whole))
+    }
+
+    fn hash_into_parent(&mut self, closed: &ClosedBlock) {
+        if let Some(parent) = self.stack.last_mut() {
+            parent.hash_child(closed.fingerprint, closed.shape);
+        }
+    }
+}
+
+impl BlockWalker {
+    /// Makes the walker compute shape fingerprints as well, i.e. fingerprints
+    /// which don't depend on instruction operands.
+    pub fn with_shapes(mut self) -> Self {
+        self.walker = self.walker.with_shapes();
+        self
+    }
+
+    /// Makes the walker compute fingerprints of canonical forms as well, i.e.
+    /// fingerprints which are equal for blocks with the same meaning.
+    pub fn with_canonical(mut self) -> Self {
+        self.canonical = Some(Vec::new());
+        self
+    }
+
+    /// Feeds the next instruction of a program to the walker.
+    ///
+    /// # Arguments
+    ///
+    /// * ins_idx - An index of the instruction in the program.
+    /// * instruction - The instruction itself.
+    ///
+    pub fn step(&mut self, ins_idx: usize, instruction: &Instruction) -> Step {
+        let step = self.walker.step(ins_idx, instruction);
+        self.follow(step, instruction)
+    }
+
+    /// Feeds the first instruction of a block to the walker, see
+    /// [`DelimiterWalker::start_block`].
+    pub fn start_block(&mut self, ins_idx: usize, instruction: &Instruction) -> Step {
+        let step = self.walker.start_block(ins_idx, instruction);
+        self.follow(step, instruction)
+    }
+
+    /// Feeds the already closed block nested into the innermost opened block,
+    /// see [`DelimiterWalker::step_over`].
+    pub fn step_over(&mut self, closed: &ClosedBlock) {
+        self.walker.step_over(closed);
+        if let (Some(frames), Some(canonical)) = (self.canonical.as_mut(), closed.canonical) {
+            if let Some(frame) = frames.last_mut() {
+                frame.hash_child(canonical);
+            }
+        }
+    }
+
+    /// Returns start positions of all blocks which are opened, but not closed
+    /// yet.
+    pub fn open_blocks(&self) -> Vec<usize> {
+        self.walker.open_blocks()
+    }
+
+    /// Updates canonical forms of opened blocks after the step of the walker.
+    fn follow(&mut self, mut step: Step, instruction: &Instruction) -> Step {
+        let frames = match self.canonical.as_mut() {
+            Some(frames) => frames,
+            None => return step,
+        };
+
+        match &mut step {
+            Step::Opened => {
+                let mut frame = CanonicalFrame::default();
+                frame.hash_instruction(instruction);
+                frames.push(frame);
+            }
+            Step::Closed(closed) => {
+                closed.canonical = Some(CanonicalFrame::close(frames, Some(instruction)));
+            }
+            Step::Split(arm) => {
+                let mut frame = frames.pop().expect("canonical frames follow blocks");
+                frame.hash_instruction(instruction);
+                let mut whole = frame.whole.take().unwrap_or_default();
+                let canonical = frame.hasher.finish();
+                whole.hash_child(canonical);
+                arm.canonical = Some(canonical);
+                let mut frame = CanonicalFrame {
+                    whole: Some(whole),
+                    ..CanonicalFrame::default()
+                };
+                frame.hash_instruction(instruction);
+                frames.push(frame);
+            }
+            Step::Joined { arm, whole } => {
+                let mut frame = frames.pop().expect("canonical frames follow blocks");
+                frame.hash_instruction(instruction);
+                let mut whole_frame = frame.whole.take().expect("the arm is not the first one");
+                let canonical = frame.hasher.finish();
+                whole_frame.hash_child(canonical);
+                arm.canonical = Some(canonical);
+                frames.push(*whole_frame);
+                whole.canonical = Some(CanonicalFrame::close(frames, None));
+            }
+            Step::Inner => {
+                if let Some(frame) = frames.last_mut() {
+                    frame.hash_instruction(instruction);
+                }
+            }
+            Step::UnmatchedEnd => {} // do nothing
+        }
+
+        step
+    }
+}
+
+impl<T> Frame<T> {
+    fn new(start: usize, opener: T, with_shapes: bool) -> Self {
+        Frame {
+            start,
+            opener,
+            hasher: DefaultHasher::new(),
+            shape_hasher: if with_shapes {
+                Some(DefaultHasher::new())
+            } else {
+                None
+            },
+            whole: None,
+        }
+    }
+
+    fn hash_child(&mut self, fingerprint: Fingerprint, shape: Option<Fingerprint>) {
+        self.hasher.write_u8(CHILD_TAG);
+        self.hasher.write_u64(fingerprint);
+        if let (Some(shape_hasher), Some(shape)) = (self.shape_hasher.as_mut(), shape) {
+            shape_hasher.write_u8(CHILD_TAG);
+            shape_hasher.write_u64(shape);
+        }
+    }
+
+    fn finish(self, end: usize, depth: usize) -> ClosedBlock {
+        ClosedBlock {
+            start: self.start,
+            end,
+            depth,
+            fingerprint: self.hasher.finish(),
+            shape: self.shape_hasher.map(|hasher| hasher.finish()),
+            canonical: None,
+            is_arm: false,
+            is_split: false,
+        }
+    }
+}
+
+impl<T: Hash> Frame<T> {
+    fn hash_instruction(&mut self, instruction: &T) {
+        self.hasher.write_u8(INSTRUCTION_TAG);
+        instruction.hash(&mut self.hasher);
+        if let Some(shape_hasher) = self.shape_hasher.as_mut() {
+            shape_hasher.write_u8(INSTRUCTION_TAG);
+            mem::discriminant(instruction).hash(shape_hasher);
+        }
+    }
+}
+
+impl CanonicalFrame {
+    /// Closes the innermost canonical form, returns its fingerprint. The
+    /// closing instruction is None for a whole split block, it belongs to the
+    /// last arm.
+    fn close(frames: &mut Vec<CanonicalFrame>, instruction: Option<&Instruction>) -> Fingerprint {
+        let mut frame = frames.pop().expect("canonical frames follow blocks");
+        if let Some(instruction) = instruction {
+            frame.hash_instruction(instruction);
+        }
+        let canonical = frame.hasher.finish();
+        if let Some(parent) = frames.last_mut() {
+            parent.hash_child(canonical);
+        }
+        canonical
+    }
+
+    fn hash_instruction(&mut self, instruction: &Instruction) {
+        if canonical::is_boolean(instruction) {
+            self.segment.push(instruction.clone());
+        } else {
+            self.flush_segment();
+            self.hasher.write_u8(INSTRUCTION_TAG);
+            Canonical::Ins(instruction.clone()).hash(&mut self.hasher);
+        }
+    }
+
+    fn hash_child(&mut self, canonical: Fingerprint) {
+        self.flush_segment();
+        self.hasher.write_u8(CHILD_TAG);
+        self.hasher.write_u64(canonical);
+    }
+
+    /// Canonicalizes collected boolean instructions and feeds them to the hasher.
+    fn flush_segment(&mut self) {
+        if !self.segment.is_empty() {
+            let segment = canonical::canonicalize_segment(&self.segment);
+            self.hasher.write_u8(INSTRUCTION_TAG);
+            Canonical::Segment(segment).hash(&mut self.hasher);
+            self.segment.clear();
+        }
+    }
+}
+
+/// Fingerprints the whole split block from its already closed arms, the same
+/// as the [`BlockWalker`] does when the last arm is closed.
+///
+/// # Panics
+///
+/// If there are no arms.
+pub(crate) fn join_arms(arms: &[&ClosedBlock]) -> ClosedBlock {
+    let (first, last) = match arms {
+        [first, .., last] => (first, last),
+        [only] => (only, only),
+        [] => panic!("a split block has arms"),
+    };
+    let mut whole = Frame::new(first.start, (), first.shape.is_some());
+    let mut canonical = first.canonical.map(|_| CanonicalFrame::default());
+    for arm in arms {
+        whole.hash_child(arm.fingerprint, arm.shape);
+        if let (Some(frame), Some(arm_canonical)) = (canonical.as_mut(), arm.canonical) {
+            frame.hash_child(arm_canonical);
+        }
+    }
+    ClosedBlock {
+        canonical: canonical.map(|frame| frame.hasher.finish()),
+        is_split: true,
+        ..whole.finish(last.end, first.depth)
+    }
+}
+
+/// Returns the fingerprint of the block if the specified instructions are
+/// exactly one well-formed block, otherwise returns None.
+pub(crate) fn fingerprint(block: &[Instruction]) -> Option<Fingerprint> {
+    walk_single_block(BlockWalker::default(), block).map(|closed| closed.fingerprint)
+}
+
+/// Returns the shape fingerprint of the block if the specified instructions
+/// are exactly one well-formed block, otherwise returns None. Blocks which
+/// differ only in instruction operands have equal shape fingerprints.
+pub(crate) fn shape_fingerprint(block: &[Instruction]) -> Option<Fingerprint> {
+    walk_single_block(BlockWalker::default().with_shapes(), block).and_then(|closed| closed.shape)
+}
+
+/// Returns the fingerprint of the canonical form of the block if the
+/// specified instructions are exactly one well-formed block, otherwise returns
+/// None. Blocks with the same meaning have equal canonical fingerprints.
+pub(crate) fn canonical_fingerprint(block: &[Instruction]) -> Option<Fingerprint> {
+    walk_single_block(BlockWalker::default().with_canonical(), block)
+        .and_then(|closed| closed.canonical)
+}
+
+fn walk_single_block(mut walker: BlockWalker, block: &[Instruction]) -> Option<ClosedBlock> {
+    walk_single(
+        |ins_idx, instruction| match ins_idx {
+            0 => walker.start_block(ins_idx, instruction),
+            _ => walker.step(ins_idx, instruction),
+        },
+        block,
+    )
+}
+
+/// Returns the fingerprint of the block of any instruction type if the
+/// specified instructions are exactly one well-formed block, otherwise returns
+/// None.
+pub(crate) fn generic_fingerprint<T: BlockDelimiter + Hash + Clone>(
+    block: &[T],
+) -> Option<Fingerprint> {
+    let mut walker = DelimiterWalker::default();
+    walk_single(
+        |ins_idx, instruction| match ins_idx {
+            0 => walker.start_block(ins_idx, instruction),
+            _ => walker.step(ins_idx, instruction),
+        },
+        block,
+    )
+    .map(|closed| closed.fingerprint)
+}
+
+/// Feeds the instructions to the walker step by step, returns the closed
+/// block only if the instructions are exactly one well-formed block. The
+/// first instruction has to be fed with `start_block` of the walker.
+fn walk_single<T, F>(mut step: F, block: &[T]) -> Option<ClosedBlock>
+where
+    F: FnMut(usize, &T) -> Step,
+{
+    let last_idx = block.len().checked_sub(1)?;
+
+    for (ins_idx, instruction) in block.iter().enumerate() {
+        match step(ins_idx, instruction) {
+            Step::Closed(closed) | Step::Joined { whole: closed, .. } if closed.start == 0 => {
+                return if closed.end == last_idx {
+                    Some(closed)
+                } else {
+                    None
+                };
+            }
+            // the first arm is a block on its own, otherwise the whole split
+            // block is closed later
+            Step::Split(arm) if arm.start == 0 && arm.end == last_idx => return Some(arm),
+            Step::UnmatchedEnd => return None,
+            Step::Inner if ins_idx == 0 => return None,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fingerprint::canonical_fingerprint;
+    use crate::fingerprint::fingerprint;
+    use crate::fingerprint::join_arms;
+    use crate::fingerprint::shape_fingerprint;
+    use crate::fingerprint::BlockWalker;
+    use crate::fingerprint::Step;
+    use crate::Instruction::*;
+    use crate::Value::*;
+    use std::ops::Range;
+
+    #[test]
+    fn nested_block_fingerprint_is_same_as_standalone() {
+        let program = vec![Begin, Push(Int(1)), If, Push(Int(2)), End, Or, End];
+        let mut walker = BlockWalker::default();
+
+        let closed: Vec<_> = program
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, ins)| match walker.step(idx, ins) {
+                Step::Closed(block) => Some(block),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(2, closed.len());
+        assert_eq!(fingerprint(&program[2..=4]), Some(closed[0].fingerprint));
+        assert_eq!(fingerprint(&program), Some(closed[1].fingerprint));
+    }
+
+    #[test]
+    fn arm_and_whole_block_fingerprints_are_same_as_standalone() {
+        let program = vec![If, Push(Int(1)), Else, Begin, End, End];
+        let mut walker = BlockWalker::default().with_canonical();
+
+        let closed: Vec<_> = program
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, ins)| walker.step(idx, ins).into_closed())
+            .collect();
+
+        assert_eq!(4, closed.len());
+        assert_eq!(fingerprint(&program[..=2]), Some(closed[0].fingerprint));
+        assert_eq!(fingerprint(&program[2..]), Some(closed[2].fingerprint));
+        assert_eq!(canonical_fingerprint(&program[2..]), closed[2].canonical);
+        assert_eq!((0, 5), (closed[3].start, closed[3].end));
+        assert_eq!(fingerprint(&program), Some(closed[3].fingerprint));
+        assert_eq!(canonical_fingerprint(&program), closed[3].canonical);
+        let arms: Vec<_> = closed.iter().map(|block| block.is_arm).collect();
+        assert_eq!(vec![true, false, true, false], arms);
+        assert!(closed[3].is_split);
+        assert_eq!(None, fingerprint(&[Else, End, End]));
+        assert_eq!(None, fingerprint(&[If, Else, End, Pop]));
+    }
+
+    #[test]
+    fn stepped_over_block_fingerprint_is_same_as_walked() {
+        let program = vec![Begin, Push(Int(1)), If, Not, Else, Pop, End, Or, End];
+        let walk = |walker: &mut BlockWalker, span: Range<usize>| {
+            span.flat_map(|idx| walker.step(idx, &program[idx]).into_closed())
+                .collect::<Vec<_>>()
+        };
+        let new_walker = || BlockWalker::default().with_shapes().with_canonical();
+
+        let walked = walk(&mut new_walker(), 0..program.len());
+        let split = walk(&mut new_walker(), 2..7);
+        let mut walker = new_walker();
+        walk(&mut walker, 0..2);
+        walker.step_over(&split[2]);
+        let stepped_over = walk(&mut walker, 7..program.len());
+
+        assert_eq!(3, split.len());
+        assert_eq!(split[2], join_arms(&[&split[0], &split[1]]));
+        assert_eq!(walked[3..], stepped_over[..]);
+    }
+
+    #[test]
+    fn different_blocks_have_different_fingerprints() {
+        let first = fingerprint(&[If, Push(Int(2)), Push(Int(3)), End]);
+        let second = fingerprint(&[If, Push(Int(3)), Push(Int(2)), End]);
+        let third = fingerprint(&[If, If, Push(Int(2)), End, Push(Int(3)), End]);
+
+        assert_ne!(first, second);
+        assert_ne!(first, third);
+    }
+
+    #[test]
+    fn not_a_single_block_has_no_fingerprint() {
+        assert_eq!(None, fingerprint(&[]));
+        assert_eq!(None, fingerprint(&[Push(Int(1)), Begin, End]));
+        assert_eq!(None, fingerprint(&[If, End, If, End]));
+        assert_eq!(None, fingerprint(&[Begin, If, End]));
+        assert_eq!(None, fingerprint(&[End]));
+    }
+
+    #[test]
+    fn shape_fingerprint_ignores_operands() {
+        let first = shape_fingerprint(&[If, Push(Int(2)), Begin, Push(Int(3)), End, End]);
+        let second = shape_fingerprint(&[If, Push(Int(7)), Begin, Push(Int(0)), End, End]);
+        let third = shape_fingerprint(&[If, Push(Int(7)), Begin, Not, End, End]);
+
+        assert!(first.is_some());
+        assert_eq!(first, second);
+        assert_ne!(first, third);
+    }
+
+    #[test]
+    fn canonical_fingerprint_ignores_equivalent_rewrites() {
+        let first = canonical_fingerprint(&[If, Push(Int(2)), Push(Int(3)), Or, If, Not, End, End]);
+        let second = canonical_fingerprint(&[
+            If,
+            Push(Int(3)),
+            Push(Int(2)),
+            Or,
+            If,
+            Not,
+            Not,
+            Not,
+            End,
+            End,
+        ]);
+        let third =
+            canonical_fingerprint(&[If, Push(Int(3)), Push(Int(2)), And, If, Not, End, End]);
+
+        assert!(first.is_some());
+        assert_eq!(first, second);
+        assert_ne!(first, third);
+    }
+}
@@ -0,0 +1,133 @@
+//!
+//! Fuzzy matching of blocks. Blocks are compared by the Levenshtein distance,
+//! i.e. by the number of inserted, deleted and substituted instructions.
+//!
+
+/// A similarity of a program block and the matched registry entry.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Similarity {
+    /// The Levenshtein distance between the block and the registry entry
+    pub distance: usize,
+    /// A score from 0 to 1, where 1 means the block matches the entry exactly
+    pub score: f64,
+}
+
+impl Similarity {
+    /// The similarity of a block which matches the registry entry exactly.
+    pub const EXACT: Similarity = Similarity {
+        distance: 0,
+        score: 1.0,
+    };
+
+    /// Creates the similarity of two sequences with the specified lengths and
+    /// the distance between them.
+    pub(crate) fn new(distance: usize, len: usize, other_len: usize) -> Self {
+        let max_len = len.max(other_len).max(1);
+        Similarity {
+            distance,
+            score: 1.0 - distance as f64 / max_len as f64,
+        }
+    }
+}
+
+/// Returns the Levenshtein distance between the entry and the block if it
+/// isn't greater than `max_distance`, otherwise returns None.
+///
+/// # Arguments
+///
+/// * entry - The registry entry.
+/// * block - The program block.
+/// * max_distance - The greatest acceptable distance.
+/// * is_equal - Returns true if the entry item matches the block item.
+///
+pub(crate) fn distance<E, B>(
+    entry: &[E],
+    block: &[B],
+    max_distance: usize,
+    is_equal: impl Fn(&E, &B) -> bool,
+) -> Option<usize> {
+    if entry.len().max(block.len()) - entry.len().min(block.len()) > max_distance {
+        return None;
+    }
+
+    let mut previous: Vec<usize> = (0..=block.len()).collect();
+    let mut current = vec![0; block.len() + 1];
+
+    for (entry_idx, entry_item) in entry.iter().enumerate() {
+        current[0] = entry_idx + 1;
+        for (block_idx, block_item) in block.iter().enumerate() {
+            let substitution = if is_equal(entry_item, block_item) {
+                0
+            } else {
+                1
+            };
+            current[block_idx + 1] = (previous[block_idx] + substitution)
+                .min(previous[block_idx + 1] + 1)
+                .min(current[block_idx] + 1);
+        }
+        if current.iter().all(|&distance| distance > max_distance) {
+            return None;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    Some(previous[block.len()]).filter(|&distance| distance <= max_distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fuzzy::distance;
+    use crate::fuzzy::Similarity;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn levenshtein_distance() {
+        let block = [If, Push(Int(2)), Not, Push(Int(3)), End];
+
+        assert_eq!(Some(0), distance(&block, &block, 0, PartialEq::eq));
+        assert_eq!(
+            Some(1),
+            distance(
+                &[If, Push(Int(2)), Push(Int(3)), End],
+                &block,
+                3,
+                PartialEq::eq
+            )
+        );
+        assert_eq!(
+            Some(2),
+            distance(
+                &[If, Push(Int(1)), Push(Int(3)), End],
+                &block,
+                3,
+                PartialEq::eq
+            )
+        );
+        assert_eq!(Some(5), distance(&block[..0], &block, 5, PartialEq::eq));
+    }
+
+    #[test]
+    fn distance_above_threshold() {
+        let block = [If, Push(Int(2)), Not, Push(Int(3)), End];
+
+        assert_eq!(
+            None,
+            distance(
+                &[If, Push(Int(1)), Push(Int(3)), End],
+                &block,
+                1,
+                PartialEq::eq
+            )
+        );
+        assert_eq!(None, distance(&[If, End], &block, 2, PartialEq::eq));
+    }
+
+    #[test]
+    fn similarity_score() {
+        assert_eq!(Similarity::EXACT, Similarity::new(0, 3, 3));
+        assert_eq!(0.75, Similarity::new(1, 3, 4).score);
+        assert_eq!(1.0, Similarity::new(0, 0, 0).score);
+    }
+}
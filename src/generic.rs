@@ -0,0 +1,257 @@
+//!
+//! Exact matching of blocks of any instruction type, which implements
+//! [`BlockDelimiter`]. It is the same algorithm as [`BlockRegistry`] uses
+//! for exact matches, but patterns, fuzzy and canonical matching are specific
+//! to [`Instruction`] and aren't supported here.
+//!
+//! [`BlockRegistry`]: crate::BlockRegistry
+//! [`Instruction`]: crate::Instruction
+//!
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::delimiter::BlockDelimiter;
+use crate::fingerprint;
+use crate::fingerprint::DelimiterWalker;
+use crate::fingerprint::Fingerprint;
+use crate::fingerprint::Step;
+use crate::MatchError;
+
+/// The registry of known execution blocks of any instruction type.
+#[derive(Debug, Clone)]
+pub struct GenericRegistry<T> {
+    /// All known blocks in the registry order
+    blocks: Vec<Vec<T>>,
+    /// Indices of known blocks by their fingerprints
+    index: HashMap<Fingerprint, Vec<usize>>,
+}
+
+/// A block of a program matched by the [`GenericRegistry`].
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+pub struct GenericBlockInfo {
+    /// An index of first block instruction in the whole program
+    block_start_idx: usize,
+    /// An index of the last (closing) block instruction in the whole program
+    block_end_idx: usize,
+    /// The number of blocks this block is nested into
+    depth: usize,
+    /// An index of this block in registry
+    registry_idx: Option<usize>,
+}
+
+impl<T: BlockDelimiter + Eq + Hash + Clone> GenericRegistry<T> {
+    /// Creates the registry from borrowed known execution blocks. The index of
+    /// each block in the registry is its position in `known_blocks`.
+    pub fn new(known_blocks: &[&[T]]) -> Self {
+        known_blocks.iter().map(|block| block.to_vec()).collect()
+    }
+
+    /// Returns the number of registered blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Returns true if the registry contains no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Adds the block to the end of the registry. A block which isn't exactly
+    /// one well-formed block is kept, but never matched. If the same block is
+    /// already registered, the new one takes precedence.
+    fn push(&mut self, block: Vec<T>) {
+        let idx = self.blocks.len();
+
+        if let Some(fingerprint) = fingerprint::generic_fingerprint(&block) {
+            let candidates = self.index.entry(fingerprint).or_default();
+            let blocks = &self.blocks;
+            candidates.retain(|&known_idx| blocks[known_idx] != block);
+            candidates.push(idx);
+        }
+
+        self.blocks.push(block);
+    }
+
+    /// Finds matches of execution blocks inside a program with this registry,
+    /// see [`BlockRegistry::find_matches`].
+    ///
+    /// [`BlockRegistry::find_matches`]: crate::BlockRegistry::find_matches
+    ///
+    /// # Arguments
+    ///
+    /// * program - The program is a vector of blocks for matching with the registry.
+    ///
+    pub fn find_matches(&self, program: &[T]) -> Result<Vec<GenericBlockInfo>, MatchError> {
+        if program.is_empty() {
+            return Err(MatchError::NoOneBlockFound);
+        }
+
+        let mut walker = DelimiterWalker::default();
+        let mut result = Vec::new();
+
+        for (ins_idx, instruction) in program.iter().enumerate() {
+            let step = walker.step(ins_idx, instruction);
+            if step == Step::UnmatchedEnd {
+                return Err(MatchError::UnmatchedEnd { position: ins_idx });
+            }
+            for closed in step.into_closed() {
+                let block = closed.slice(program);
+                let registry_idx = self
+                    .index
+                    .get(&closed.fingerprint)
+                    .into_iter()
+                    .flatten()
+                    .copied()
+                    .find(|&idx| self.blocks[idx] == block);
+                result.push(GenericBlockInfo {
+                    block_start_idx: closed.start,
+                    block_end_idx: closed.end,
+                    depth: closed.depth,
+                    registry_idx,
+                });
+            }
+        }
+
+        let open_blocks = walker.open_blocks();
+        if open_blocks.is_empty() {
+            Ok(result)
+        } else {
+            Err(MatchError::UnclosedBlocks {
+                starts: open_blocks,
+            })
+        }
+    }
+}
+
+impl GenericBlockInfo {
+    /// Returns an index of the first block instruction in the whole program.
+    pub fn block_start_idx(&self) -> usize {
+        self.block_start_idx
+    }
+
+    /// Returns an index of the last (closing) block instruction in the whole
+    /// program.
+    pub fn block_end_idx(&self) -> usize {
+        self.block_end_idx
+    }
+
+    /// Returns the number of blocks this block is nested into, top-level
+    /// blocks have depth 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns an index of this block in the registry, if the block is known.
+    pub fn registry_idx(&self) -> Option<usize> {
+        self.registry_idx
+    }
+}
+
+impl<T> Default for GenericRegistry<T> {
+    fn default() -> Self {
+        GenericRegistry {
+            blocks: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+}
+
+impl<T: BlockDelimiter + Eq + Hash + Clone> From<Vec<Vec<T>>> for GenericRegistry<T> {
+    /// Creates the registry from owned known execution blocks.
+    fn from(known_blocks: Vec<Vec<T>>) -> Self {
+        known_blocks.into_iter().collect()
+    }
+}
+
+impl<T: BlockDelimiter + Eq + Hash + Clone> std::iter::FromIterator<Vec<T>> for GenericRegistry<T> {
+    fn from_iter<I: IntoIterator<Item = Vec<T>>>(known_blocks: I) -> Self {
+        let mut registry = GenericRegistry::default();
+        for block in known_blocks {
+            registry.push(block);
+        }
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::Bracket::*;
+    use crate::BlockDelimiter;
+    use crate::BlockRegistry;
+    use crate::Delimiter;
+    use crate::GenericRegistry;
+    use crate::Instruction::*;
+    use crate::MatchError;
+    use crate::Value::*;
+
+    /// Brackets of different kinds, a closing bracket closes only a block
+    /// opened by the bracket of the same kind.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    enum Bracket {
+        Open(char),
+        Close(char),
+        Other(u8),
+    }
+
+    impl BlockDelimiter for Bracket {
+        fn delimiter(&self) -> Delimiter {
+            match self {
+                Bracket::Open(_) => Delimiter::Open,
+                Bracket::Close(_) => Delimiter::Close,
+                Bracket::Other(_) => Delimiter::Neutral,
+            }
+        }
+
+        fn closes(&self, opener: &Self) -> bool {
+            matches!((opener, self), (Bracket::Open(open), Bracket::Close(close)) if open == close)
+        }
+    }
+
+    #[test]
+    fn custom_instruction_set() {
+        let registry = GenericRegistry::new(&[&[Open('('), Other(1), Close('(')]]);
+        let program = vec![Open('['), Open('('), Other(1), Close('('), Close('[')];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(2, result.len());
+        assert_eq!(Some(0), result[0].registry_idx());
+        assert_eq!(1, result[0].block_start_idx());
+        assert_eq!(3, result[0].block_end_idx());
+        assert_eq!(1, result[0].depth());
+        assert_eq!(None, result[1].registry_idx());
+    }
+
+    #[test]
+    fn closer_of_another_kind_is_unmatched() {
+        let registry = GenericRegistry::default();
+
+        let result = registry.find_matches(&[Open('('), Close('['), Close('(')]);
+
+        assert_eq!(Err(MatchError::UnmatchedEnd { position: 1 }), result);
+    }
+
+    #[test]
+    fn instructions_are_matched_as_by_block_registry() {
+        let known_blocks: Vec<&[_]> = vec![&[If, Push(Int(2)), End], &[Begin, End]];
+        let program = vec![Begin, If, Push(Int(2)), End, If, End, End];
+
+        let generic = GenericRegistry::new(&known_blocks)
+            .find_matches(&program)
+            .unwrap();
+        let specific = BlockRegistry::new(&known_blocks)
+            .find_matches(&program)
+            .unwrap();
+
+        let generic: Vec<_> = generic
+            .iter()
+            .map(|info| (info.block_start_idx(), info.registry_idx()))
+            .collect();
+        let specific: Vec<_> = specific
+            .iter()
+            .map(|info| (info.block_start_idx(), info.registry_idx()))
+            .collect();
+        assert_eq!(specific, generic);
+    }
+}
@@ -0,0 +1,710 @@
+//!
+//! Re-matching of a program after edits. Only the innermost block which
+//! contains an edit is matched again: its nested blocks which are touched by
+//! the edit are walked again, then the block itself and its ancestors are
+//! fingerprinted again from cached fingerprints of their nested blocks. All
+//! other blocks are only moved by the difference of lengths.
+//!
+//! An edit which changes the structure of its innermost block, e.g. closes
+//! the block before its 'End', is matched again along with the whole top-level
+//! blocks it touches.
+//!
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::fingerprint;
+use crate::fingerprint::ClosedBlock;
+use crate::fingerprint::Step;
+use crate::BlockInfo;
+use crate::BlockRegistry;
+use crate::Instruction;
+use crate::MatchError;
+
+/// Keeps a program along with its matched blocks up to date while the program
+/// is being edited.
+pub struct IncrementalMatcher<'r> {
+    registry: &'r BlockRegistry,
+    program: Vec<Instruction>,
+    /// Matched blocks of the program in the order of their last instructions,
+    /// the same as [`BlockRegistry::find_matches`] returns
+    blocks: Vec<BlockInfo>,
+    /// Fingerprints of matched blocks in the same order, a block is
+    /// fingerprinted again from fingerprints of its nested blocks
+    closed: Vec<ClosedBlock>,
+}
+
+/// An edit of a program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    /// Inserts instructions before the position, the position may be the
+    /// length of the program.
+    Insert {
+        position: usize,
+        instructions: Vec<Instruction>,
+    },
+    /// Deletes instructions in the range.
+    Delete { range: Range<usize> },
+    /// Replaces instructions in the range with the other instructions.
+    Replace {
+        range: Range<usize>,
+        instructions: Vec<Instruction>,
+    },
+}
+
+/// Blocks which were affected by an edit. Blocks which were only moved by the
+/// edit aren't reported.
+#[derive(Debug, Default, PartialEq)]
+pub struct MatchDiff {
+    /// New blocks, in the order of their last instructions
+    pub added: Vec<BlockInfo>,
+    /// Blocks which no longer exist, positions are of the program before the
+    /// edit
+    pub removed: Vec<BlockInfo>,
+    /// Blocks which still exist, but were matched differently, pairs of the
+    /// block before and after the edit
+    pub changed: Vec<(BlockInfo, BlockInfo)>,
+}
+
+/// Blocks of the edited program which replace a contiguous run of blocks of
+/// the program before the edit.
+struct Rematched {
+    /// Indices of the replaced blocks
+    replaced: Range<usize>,
+    blocks: Vec<BlockInfo>,
+    closed: Vec<ClosedBlock>,
+}
+
+impl<'r> IncrementalMatcher<'r> {
+    /// Matches the whole program with the registry, see
+    /// [`BlockRegistry::find_matches`].
+    pub fn new(registry: &'r BlockRegistry, program: Vec<Instruction>) -> Result<Self, MatchError> {
+        let (blocks, closed) = match_span(registry, &program, 0..program.len(), 0)?;
+        Ok(IncrementalMatcher {
+            registry,
+            program,
+            blocks,
+            closed,
+        })
+    }
+
+    /// Returns the current program.
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// Returns matched blocks of the current program, the same as
+    /// [`BlockRegistry::find_matches`] returns for it.
+    pub fn blocks(&self) -> &[BlockInfo] {
+        &self.blocks
+    }
+
+    /// Applies the edit to the program and re-matches the affected blocks.
+    /// Fails if the edited program can't be matched, the program and its
+    /// blocks are left as they were before the edit then.
+    ///
+    /// # Panics
+    ///
+    /// If the edit is out of the program.
+    pub fn apply(&mut self, edit: Edit) -> Result<MatchDiff, MatchError> {
+        let (range, instructions) = match edit {
+            Edit::Insert {
+                position,
+                instructions,
+            } => (position..position, instructions),
+            Edit::Delete { range } => (range, Vec::new()),
+            Edit::Replace {
+                range,
+                instructions,
+            } => (range, instructions),
+        };
+        assert!(
+            range.start <= range.end && range.end <= self.program.len(),
+            "the edit {:?} is out of the program of {} instructions",
+            range,
+            self.program.len()
+        );
+        if self.program.len() - range.len() + instructions.len() == 0 {
+            return Err(MatchError::NoOneBlockFound);
+        }
+
+        // a position after the edit in the program before the edit is moved by
+        // the difference of lengths
+        let removed_len = range.len();
+        let inserted_len = instructions.len();
+        let moved = |position: usize| {
+            if position >= range.end {
+                position - removed_len + inserted_len
+            } else {
+                position
+            }
+        };
+
+        // the program is edited in place and restored if it can't be matched
+        let removed: Vec<_> = self.program.splice(range.clone(), instructions).collect();
+        let nested = self.enclosing_block(&range).and_then(|idx| {
+            let depth = self.blocks[idx].depth + 1;
+            let rematched = self.rematch(&range, depth, moved).ok()?;
+            Some((rematched, self.ancestors(idx)))
+        });
+        let (rematched, ancestors) = match nested {
+            Some(nested) => nested,
+            None => match self.rematch(&range, 0, moved) {
+                Ok(rematched) => (rematched, Vec::new()),
+                Err(err) => {
+                    let inserted = range.start..range.start + inserted_len;
+                    self.program.splice(inserted, removed);
+                    return Err(err);
+                }
+            },
+        };
+
+        let Rematched {
+            replaced,
+            blocks,
+            closed,
+        } = rematched;
+        let old_blocks: Vec<_> = self
+            .blocks
+            .splice(replaced.clone(), blocks.iter().cloned())
+            .collect();
+        self.closed.splice(replaced.clone(), closed);
+        let ancestors: Vec<_> = ancestors
+            .into_iter()
+            .map(|idx| idx - replaced.len() + blocks.len())
+            .collect();
+        let old_ancestors: Vec<_> = ancestors
+            .iter()
+            .map(|&idx| self.blocks[idx].clone())
+            .collect();
+        let following = replaced.start + blocks.len();
+        for (info, closed) in self.blocks[following..]
+            .iter_mut()
+            .zip(&mut self.closed[following..])
+        {
+            move_block(info, moved);
+            closed.start = moved(closed.start);
+            closed.end = moved(closed.end);
+        }
+
+        // a block which survived the edit has both its first and last
+        // instructions outside the edited range
+        let mut survived = HashMap::new();
+        let mut diff = MatchDiff::default();
+        for info in old_blocks {
+            let is_edited = |position: usize| range.contains(&position);
+            if is_edited(info.block_start_idx) || is_edited(info.block_end_idx) {
+                diff.removed.push(info);
+            } else {
+                let key = (moved(info.block_start_idx), moved(info.block_end_idx));
+                survived.insert(key, info);
+            }
+        }
+        for info in blocks {
+            match survived.remove(&(info.block_start_idx, info.block_end_idx)) {
+                Some(old) => {
+                    let mut old_moved = old.clone();
+                    move_block(&mut old_moved, moved);
+                    if old_moved != info {
+                        diff.changed.push((old, info));
+                    }
+                }
+                None => diff.added.push(info),
+            }
+        }
+        diff.removed.extend(survived.into_values());
+        diff.removed.sort_unstable_by_key(|info| info.block_end_idx);
+
+        // ancestors of the rematched blocks contain the edit, so they always
+        // survive it, the innermost one is fingerprinted first
+        for (idx, old) in ancestors.into_iter().zip(old_ancestors) {
+            let closed = self.fingerprint_block(idx);
+            let info = self
+                .registry
+                .match_block(&closed, closed.slice(&self.program))
+                .expect("the block is sliced from the program");
+            if info != self.blocks[idx] {
+                diff.changed.push((old, info.clone()));
+            }
+            self.blocks[idx] = info;
+            self.closed[idx] = closed;
+        }
+
+        Ok(diff)
+    }
+
+    /// Returns an index of the innermost block which contains the edit of the
+    /// range, i.e. the edit is between the first and the last instructions of
+    /// the block. A whole split block contains only its arms, so an edit
+    /// between its arms is enclosed by its parent.
+    fn enclosing_block(&self, range: &Range<usize>) -> Option<usize> {
+        let first = self
+            .blocks
+            .partition_point(|info| info.block_end_idx < range.end);
+        self.blocks[first..]
+            .iter()
+            .zip(&self.closed[first..])
+            .position(|(info, closed)| info.block_start_idx < range.start && !closed.is_split)
+            .map(|idx| first + idx)
+    }
+
+    /// Returns indices of the block and all blocks it is nested into, the
+    /// innermost block first.
+    fn ancestors(&self, idx: usize) -> Vec<usize> {
+        let mut ancestors = vec![idx];
+        let mut block = &self.closed[idx];
+        for (idx, closed) in self.closed.iter().enumerate().skip(idx + 1) {
+            if block.depth == 0 && !block.is_arm {
+                break;
+            }
+            // the parent of an arm is the whole split block right after its
+            // last arm, the parent of another block is the first block after
+            // all its next siblings
+            let is_parent = if block.is_arm {
+                closed.is_split && closed.depth == block.depth
+            } else {
+                closed.depth < block.depth
+            };
+            if is_parent {
+                ancestors.push(idx);
+                block = closed;
+            }
+        }
+        ancestors
+    }
+
+    /// Walks and matches again the edited range of the program along with all
+    /// blocks nested at the depth which it touches. The range and indices of
+    /// replaced blocks are of the program before the edit, `self.program` is
+    /// already edited.
+    fn rematch(
+        &self,
+        range: &Range<usize>,
+        depth: usize,
+        moved: impl Fn(usize) -> usize,
+    ) -> Result<Rematched, MatchError> {
+        let span = self.affected_span(range, depth);
+        let first = self
+            .blocks
+            .partition_point(|info| info.block_end_idx < span.start);
+        let last = self
+            .blocks
+            .partition_point(|info| info.block_end_idx < span.end);
+        let (blocks, closed) = match_span(
+            self.registry,
+            &self.program,
+            span.start..moved(span.end),
+            depth,
+        )?;
+        Ok(Rematched {
+            replaced: first..last,
+            blocks,
+            closed,
+        })
+    }
+
+    /// Returns the span of the program which has to be matched again after the
+    /// edit of the range: the range itself along with all blocks nested at the
+    /// depth which intersect it. An insertion intersects a block if it is
+    /// between the first and the last instructions of the block.
+    fn affected_span(&self, range: &Range<usize>, depth: usize) -> Range<usize> {
+        let mut span = range.clone();
+        let last = if range.is_empty() {
+            range.start
+        } else {
+            range.end - 1
+        };
+        for &position in [range.start, last].iter() {
+            if let Some(block) = self.nested_block_at(position, depth) {
+                if block.block_start_idx < range.end {
+                    span.start = span.start.min(block.block_start_idx);
+                    span.end = span.end.max(block.block_end_idx + 1);
+                }
+            }
+        }
+
+        // arms of the same 'If' share the 'Else' instruction, so they are
+        // matched together
+        let before = self
+            .blocks
+            .partition_point(|info| info.block_end_idx < span.start);
+        if let Some(arm) = self.blocks.get(before) {
+            if arm.block_end_idx == span.start && arm.depth == depth {
+                span.start = arm.block_start_idx;
+            }
+        }
+        if let Some(arm) = self.nested_block_at(span.end, depth) {
+            if arm.block_start_idx + 1 == span.end {
+                span.end = arm.block_end_idx + 1;
+            }
+        }
+        span
+    }
+
+    /// Returns the first block nested at the depth which ends at the position
+    /// or after it, None if a less nested block ends before it.
+    fn nested_block_at(&self, position: usize, depth: usize) -> Option<&BlockInfo> {
+        let first = self
+            .blocks
+            .partition_point(|info| info.block_end_idx < position);
+        self.blocks[first..]
+            .iter()
+            .find(|info| info.depth <= depth)
+            .filter(|info| info.depth == depth)
+    }
+
+    /// Fingerprints the block again from its own instructions and cached
+    /// fingerprints of its directly nested blocks. A whole split block is
+    /// fingerprinted from its arms only.
+    fn fingerprint_block(&self, idx: usize) -> ClosedBlock {
+        let block = &self.closed[idx];
+
+        // directly nested blocks in the reverse order, all blocks nested into a
+        // block are right before it
+        let mut children = Vec::new();
+        let mut next_idx = idx;
+        while next_idx > 0 && self.closed[next_idx - 1].end > block.start {
+            let child = &self.closed[next_idx - 1];
+            children.push(child);
+            next_idx = self
+                .closed
+                .partition_point(|closed| closed.end <= child.start);
+        }
+
+        if block.is_split {
+            children.reverse();
+            return fingerprint::join_arms(&children);
+        }
+
+        let mut walker = self.registry.walker();
+        walker.start_block(block.start, &self.program[block.start]);
+        let mut position = block.start + 1;
+        for child in children.into_iter().rev() {
+            for ins_idx in position..child.start {
+                walker.step(ins_idx, &self.program[ins_idx]);
+            }
+            walker.step_over(child);
+            position = child.end + 1;
+        }
+        for ins_idx in position..block.end {
+            walker.step(ins_idx, &self.program[ins_idx]);
+        }
+
+        match walker.step(block.end, &self.program[block.end]) {
+            // an arm which isn't the first one is walked on its own here
+            Step::Closed(closed) | Step::Split(closed) => ClosedBlock {
+                depth: block.depth,
+                is_arm: block.is_arm,
+                ..closed
+            },
+            step => unreachable!(
+                "the block is closed by its last instruction, not {:?}",
+                step
+            ),
+        }
+    }
+}
+
+/// Walks and matches blocks of the span of the program, the span is nested at
+/// the depth. Returns matched blocks along with their fingerprints.
+fn match_span(
+    registry: &BlockRegistry,
+    program: &[Instruction],
+    span: Range<usize>,
+    depth: usize,
+) -> Result<(Vec<BlockInfo>, Vec<ClosedBlock>), MatchError> {
+    let mut blocks = Vec::new();
+    let mut fingerprints = Vec::new();
+    registry.walk(program, span, |step| {
+        for mut closed in step.into_closed() {
+            closed.depth += depth;
+            blocks.push(registry.match_block(&closed, closed.slice(program))?);
+            fingerprints.push(closed);
+        }
+        Ok(())
+    })?;
+    Ok((blocks, fingerprints))
+}
+
+/// Moves all positions of the block as the function tells.
+fn move_block(info: &mut BlockInfo, moved: impl Fn(usize) -> usize) {
+    info.block_start_idx = moved(info.block_start_idx);
+    info.block_end_idx = moved(info.block_end_idx);
+    for (start, end) in &mut info.holes {
+        *start = moved(*start);
+        *end = moved(*end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::incremental::Edit;
+    use crate::incremental::IncrementalMatcher;
+    use crate::incremental::MatchDiff;
+    use crate::tests::matched;
+    use crate::tests::not_matched;
+    use crate::BlockKind;
+    use crate::BlockRegistry;
+    use crate::Instruction;
+    use crate::Instruction::*;
+    use crate::MatchError;
+    use crate::Operand::Any;
+    use crate::Token;
+    use crate::Token::Ins;
+    use crate::Value::*;
+
+    #[test]
+    fn edits_are_same_as_whole_program_matching() {
+        let registry = BlockRegistry::new(&[
+            &[If, Push(Int(2)), End],
+            &[Begin, Push(Int(1)), End],
+            &[Else, End],
+        ]);
+        let program = vec![Begin, If, Push(Int(2)), End, End, If, Else, End, Begin, End];
+        let mut matcher = IncrementalMatcher::new(&registry, program).unwrap();
+
+        let edits = vec![
+            Edit::Insert {
+                position: 3,
+                instructions: vec![Not],
+            },
+            Edit::Delete { range: 3..4 },
+            Edit::Replace {
+                range: 2..3,
+                instructions: vec![Push(Int(1))],
+            },
+            Edit::Insert {
+                position: 10,
+                instructions: vec![Begin, Push(Int(1)), End],
+            },
+            Edit::Insert {
+                position: 7,
+                instructions: vec![Loop, Break, End],
+            },
+            Edit::Delete { range: 0..5 },
+            Edit::Insert {
+                position: 0,
+                instructions: vec![Push(Int(7))],
+            },
+            Edit::Replace {
+                range: 2..3,
+                instructions: vec![Push(Int(3)), End, If],
+            },
+        ];
+
+        for edit in edits {
+            matcher.apply(edit.clone()).unwrap();
+            assert_eq!(
+                registry.find_matches(matcher.program()).unwrap(),
+                matcher.blocks(),
+                "after {:?}",
+                edit
+            );
+        }
+    }
+
+    #[test]
+    fn nested_edits_are_same_as_whole_program_matching() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(If), Token::Push(Any), Ins(Else)],
+            vec![Ins(Begin), Ins(Push(Bool(true))), Ins(Not), Ins(End)],
+            vec![Ins(Loop), Token::AnyBlock, Ins(End)],
+            vec![Ins(Else), Token::AnySequence, Ins(End)],
+            vec![
+                Ins(If),
+                Ins(Push(Int(1))),
+                Ins(Else),
+                Ins(Begin),
+                Ins(Push(Bool(true))),
+                Ins(Not),
+                Ins(End),
+                Ins(End),
+            ],
+        ])
+        .with_canonical_matching()
+        .with_fuzzy_matching(1);
+        let program = vec![
+            Begin,
+            Loop,
+            Begin,
+            Push(Bool(false)),
+            End,
+            End,
+            If,
+            Push(Int(1)),
+            Else,
+            Begin,
+            Push(Bool(true)),
+            Not,
+            End,
+            End,
+            Begin,
+            End,
+            End,
+        ];
+        let mut matcher = IncrementalMatcher::new(&registry, program).unwrap();
+
+        let edits = vec![
+            Edit::Insert {
+                position: 4,
+                instructions: vec![Not],
+            },
+            Edit::Replace {
+                range: 3..5,
+                instructions: vec![Push(Bool(true)), Not],
+            },
+            // closes the nested block before its 'End'
+            Edit::Insert {
+                position: 4,
+                instructions: vec![End, Begin],
+            },
+            Edit::Delete { range: 4..6 },
+            // inside the first arm
+            Edit::Insert {
+                position: 8,
+                instructions: vec![Push(Int(2))],
+            },
+            Edit::Delete { range: 8..9 },
+            // inside a block of the second arm
+            Edit::Insert {
+                position: 13,
+                instructions: vec![Not],
+            },
+            // 'Else' of the arms
+            Edit::Replace {
+                range: 8..9,
+                instructions: vec![Else],
+            },
+            Edit::Delete { range: 9..10 },
+            Edit::Insert {
+                position: 18,
+                instructions: vec![Loop, Begin, End, End],
+            },
+            // unbalanced, so it isn't applied
+            Edit::Delete { range: 0..1 },
+            Edit::Replace {
+                range: 0..1,
+                instructions: vec![Loop],
+            },
+        ];
+
+        for edit in edits {
+            let program = matcher.program().to_vec();
+            let expected = registry.find_matches(&edited(&program, &edit));
+
+            let result = matcher
+                .apply(edit.clone())
+                .map(|_| matcher.blocks().to_vec());
+
+            assert_eq!(expected, result, "after {:?}", edit);
+            if result.is_err() {
+                assert_eq!(program, matcher.program());
+            }
+        }
+    }
+
+    #[test]
+    fn diff_of_edited_block_and_ancestors() {
+        let registry = BlockRegistry::new(&[
+            &[If, Push(Int(2)), End],
+            &[If, End],
+            &[Begin, If, End, If, End, End],
+        ]);
+        let program = vec![Begin, If, End, If, End, End, Begin, End];
+        let mut matcher = IncrementalMatcher::new(&registry, program).unwrap();
+
+        let diff = matcher
+            .apply(Edit::Insert {
+                position: 2,
+                instructions: vec![Push(Int(2))],
+            })
+            .unwrap();
+
+        assert_eq!(
+            vec![Begin, If, Push(Int(2)), End, If, End, End, Begin, End],
+            matcher.program()
+        );
+        assert_eq!(
+            MatchDiff {
+                added: vec![],
+                removed: vec![],
+                changed: vec![
+                    (
+                        matched(1, 2, 1, BlockKind::If, 1),
+                        matched(1, 3, 1, BlockKind::If, 0)
+                    ),
+                    (
+                        matched(0, 5, 0, BlockKind::Begin, 2),
+                        not_matched(0, 6, 0, BlockKind::Begin)
+                    ),
+                ],
+            },
+            diff
+        );
+    }
+
+    #[test]
+    fn diff_of_added_and_removed_blocks() {
+        let registry = BlockRegistry::new(&[&[If, End]]);
+        let program = vec![Begin, If, End, End];
+        let mut matcher = IncrementalMatcher::new(&registry, program).unwrap();
+
+        let diff = matcher
+            .apply(Edit::Replace {
+                range: 1..3,
+                instructions: vec![Loop, End],
+            })
+            .unwrap();
+
+        assert_eq!(vec![not_matched(1, 2, 1, BlockKind::Loop)], diff.added);
+        assert_eq!(1, diff.removed.len());
+        assert_eq!(Some(0), diff.removed[0].registry_idx());
+        assert!(diff.changed.is_empty());
+    }
+
+    #[test]
+    fn failed_edit_is_not_applied() {
+        let registry = BlockRegistry::default();
+        let program = vec![Begin, End, If, End];
+        let mut matcher = IncrementalMatcher::new(&registry, program.clone()).unwrap();
+
+        let unclosed = matcher.apply(Edit::Delete { range: 1..2 });
+        let unmatched = matcher.apply(Edit::Insert {
+            position: 4,
+            instructions: vec![End],
+        });
+        let empty = matcher.apply(Edit::Delete { range: 0..4 });
+
+        assert_eq!(
+            Err(MatchError::UnclosedBlocks { starts: vec![0] }),
+            unclosed
+        );
+        assert_eq!(Err(MatchError::UnmatchedEnd { position: 4 }), unmatched);
+        assert_eq!(Err(MatchError::NoOneBlockFound), empty);
+        assert_eq!(program, matcher.program());
+        assert_eq!(
+            Ok(MatchDiff::default()),
+            matcher.apply(Edit::Insert {
+                position: 2,
+                instructions: vec![]
+            })
+        );
+    }
+
+    /// Applies the edit to a copy of the program.
+    fn edited(program: &[Instruction], edit: &Edit) -> Vec<Instruction> {
+        let mut program = program.to_vec();
+        match edit.clone() {
+            Edit::Insert {
+                position,
+                instructions,
+            } => program.splice(position..position, instructions),
+            Edit::Delete { range } => program.splice(range, vec![]),
+            Edit::Replace {
+                range,
+                instructions,
+            } => program.splice(range, instructions),
+        };
+        program
+    }
+}
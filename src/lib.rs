@@ -2,13 +2,88 @@
 //! Provides an algorithm which matches execution blocks inside a program with
 //! some registry of known execution blocks.
 //!
+//! The optional `serde` feature derives `Serialize` and `Deserialize` for
+//! [`Instruction`], [`BlockInfo`] and [`MatchError`].
+//!
 
-use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
 use std::fmt::Display;
 use std::fmt::Formatter;
 
+pub use crate::asm::parse_pattern;
+pub use crate::asm::parse_program;
+pub use crate::asm::Listing;
+pub use crate::asm::ParseError;
+pub use crate::asm::ParseErrorKind;
+pub use crate::asm::PatternListing;
+pub use crate::bytecode::decode;
+pub use crate::bytecode::encode;
+pub use crate::bytecode::DecodeError;
+pub use crate::bytecode::DecodeErrorKind;
+pub use crate::bytecode::Decoder;
+pub use crate::bytecode::MAGIC;
+pub use crate::clones::find_duplicate_blocks;
+pub use crate::clones::find_equivalent_blocks;
+pub use crate::clones::BlockPosition;
+pub use crate::clones::DuplicateGroup;
+pub use crate::delimiter::BlockDelimiter;
+pub use crate::delimiter::Delimiter;
+pub use crate::fuzzy::Similarity;
+pub use crate::generic::GenericBlockInfo;
+pub use crate::generic::GenericRegistry;
+pub use crate::incremental::Edit;
+pub use crate::incremental::IncrementalMatcher;
+pub use crate::incremental::MatchDiff;
+pub use crate::mining::Candidate;
+pub use crate::mining::RegistryMiner;
+pub use crate::occurrence::Occurrence;
+pub use crate::outline::expand_calls;
+pub use crate::outline::outline_blocks;
+pub use crate::outline::ExpandError;
+pub use crate::pattern::Captures;
+pub use crate::pattern::Holes;
+pub use crate::pattern::Operand;
+pub use crate::pattern::Token;
+pub use crate::registry::BlockRegistry;
+pub use crate::registry_file::parse_registry;
+pub use crate::registry_file::RegistryEntry;
+pub use crate::registry_file::RegistryFile;
+pub use crate::registry_file::SCHEMA_VERSION;
+pub use crate::streaming::StreamMatches;
+pub use crate::streaming::StreamingMatcher;
+pub use crate::tree::BlockNode;
+pub use crate::tree::BlockTree;
+pub use crate::tree::PostOrder;
+pub use crate::tree::PreOrder;
+pub use crate::value::Value;
+pub use crate::value::ValueType;
+pub use crate::vm::validate_block;
+pub use crate::vm::Vm;
+pub use crate::vm::VmError;
+pub use crate::vm::VmErrorKind;
+
+mod asm;
+mod batch;
+mod bytecode;
+mod canonical;
+mod clones;
+mod delimiter;
+mod fingerprint;
+mod fuzzy;
+mod generic;
+mod incremental;
+mod mining;
+mod occurrence;
+mod outline;
+mod pattern;
+mod registry;
+mod registry_file;
+mod streaming;
+mod tree;
+mod value;
+mod vm;
+
 /// Finds matches of execution blocks inside a program with the specified
 /// registry of known blocks. For each block in the program returns a vector with
 /// block start position and block index in the registry. Note that order in
@@ -16,6 +91,9 @@ use std::fmt::Formatter;
 /// It means that at the first position of the result vector will be placed the
 /// first closed block (not the first started block).
 ///
+/// This function builds the registry index on each call, prefer
+/// [`BlockRegistry`] for matching many programs against the same registry.
+///
 /// # Arguments
 ///
 /// * known_blocks - The registry of known execution blocks.
@@ -25,86 +103,193 @@ pub fn find_matches(
     known_blocks: &[&[Instruction]],
     program: &[Instruction],
 ) -> Result<Vec<BlockInfo>, MatchError> {
-    use self::Instruction::*;
-
-    if program.is_empty() {
-        return Err(MatchError::NoOneBlockFound);
-    }
-
-    let register: HashMap<&[Instruction], usize> = known_blocks
-        .iter()
-        .enumerate()
-        .map(|(idx, ins)| (*ins, idx))
-        .collect();
-
-    let mut block_stack = Vec::new();
-
-    let result = program
-        .iter()
-        .enumerate()
-        .filter_map(|(ins_idx, instruction)| {
-            match instruction {
-                Begin | If => {
-                    block_stack.push(ins_idx);
-                    None
-                }
-                End => block_stack
-                    .pop()
-                    .map(|block_start_idx| {
-                        let block = &program[block_start_idx..=ins_idx];
-
-                        Ok(BlockInfo {
-                            block_start_idx,
-                            registry_idx: register.get(block).map(Clone::clone),
-                        })
-                    })
-                    .or_else(|| {
-                        let msg = format!(
-                            "Attempt to close the non-existent block, at the position: {}",
-                            ins_idx
-                        );
-                        Some(Err(MatchError::InvalidBlock(msg)))
-                    }),
-                _ => None, // do nothing
-            }
-        })
-        .collect();
-
-    if block_stack.is_empty() {
-        result
-    } else {
-        let msg = format!(
-            "Next blocks weren't be closed. The start blocks positions: {:?}",
-            block_stack
-        );
-        Err(MatchError::InvalidBlock(msg))
-    }
+    BlockRegistry::new(known_blocks).find_matches(program)
 }
 
 /// VM instruction set.
-#[derive(Debug, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
-    Push(usize),
+    Push(Value),
     Or,
     And,
     Not,
     If,
     Begin,
     End,
+    Else,
+    Loop,
+    Break,
+    Xor,
+    Dup,
+    Pop,
+    Swap,
+    /// A call of the registry block with the index, see [`outline_blocks`]
+    Call(usize),
 }
 
-#[derive(Debug, PartialOrd, PartialEq)]
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BlockInfo {
     /// An index of first block instruction in the whole program
     block_start_idx: usize,
+    /// An index of the last ('End' or 'Else') block instruction in the whole
+    /// program
+    block_end_idx: usize,
+    /// The number of blocks this block is nested into
+    depth: usize,
+    /// The instruction which opens the block
+    kind: BlockKind,
     /// An index of this block in registry
     registry_idx: Option<usize>,
+    /// Operands captured by the matched registry pattern
+    captures: Captures,
+    /// Spans of nested instructions bound to holes of the matched registry
+    /// pattern, positions are in the whole program
+    holes: Holes,
+    /// A similarity of the block and the matched registry entry
+    similarity: Option<Similarity>,
+}
+
+/// An instruction which opens a block.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlockKind {
+    Begin,
+    If,
+    Loop,
+    /// The second arm of an 'If' block, which is opened by 'Else'
+    Else,
+}
+
+impl BlockInfo {
+    /// Returns an index of the first block instruction in the whole program.
+    pub fn block_start_idx(&self) -> usize {
+        self.block_start_idx
+    }
+
+    /// Returns an index of the last ('End' or 'Else') block instruction in the
+    /// whole program. A block which was implicitly closed by lenient matching
+    /// ends after the last instruction of the program.
+    pub fn block_end_idx(&self) -> usize {
+        self.block_end_idx
+    }
+
+    /// Returns the number of instructions in the block, including 'End'.
+    pub fn block_len(&self) -> usize {
+        self.block_end_idx - self.block_start_idx + 1
+    }
+
+    /// Returns the number of blocks this block is nested into, top-level
+    /// blocks have depth 0.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the instruction which opens the block.
+    pub fn kind(&self) -> BlockKind {
+        self.kind
+    }
+
+    /// Returns an index of this block in the registry, if the block is known.
+    pub fn registry_idx(&self) -> Option<usize> {
+        self.registry_idx
+    }
+
+    /// Returns operands captured by the matched registry pattern.
+    pub fn captures(&self) -> &[(String, Value)] {
+        &self.captures
+    }
+
+    /// Returns spans of nested instructions bound to holes of the matched
+    /// registry pattern, positions are in the whole program.
+    pub fn holes(&self) -> &[(usize, usize)] {
+        &self.holes
+    }
+
+    /// Returns the similarity of the block and the matched registry entry, if
+    /// the block is known.
+    pub fn similarity(&self) -> Option<Similarity> {
+        self.similarity
+    }
+
+    /// Borrows instructions of this block from the matched program. Missing
+    /// 'End' instructions of implicitly closed blocks aren't included.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the program is shorter than the start of the block, i.e. it
+    /// isn't the matched program.
+    pub fn block<'a>(&self, program: &'a [Instruction]) -> &'a [Instruction] {
+        let end = program.len().min(self.block_end_idx + 1);
+        &program[self.block_start_idx..end]
+    }
+}
+
+/// Matches of a program with unbalanced blocks, see
+/// [`BlockRegistry::find_matches_lenient`].
+#[derive(Debug, Default, PartialOrd, PartialEq)]
+pub struct LenientMatches {
+    /// All matched blocks, including implicitly closed ones
+    pub blocks: Vec<BlockInfo>,
+    /// Structural problems of the program in the order they were found
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A structural problem of a program which was skipped over while matching.
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+pub struct Diagnostic {
+    /// An index of the problem instruction in the program
+    pub position: usize,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, PartialOrd, PartialEq)]
+pub enum DiagnosticKind {
+    /// 'End' closes a block which wasn't opened or 'Else' closes a block which
+    /// isn't an 'If' block, the instruction is ignored.
+    UnmatchedEnd,
+    /// The block opened by the instruction is never closed, it is implicitly
+    /// closed at the end of the program.
+    UnclosedBlock,
 }
 
 #[derive(Debug, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MatchError {
     NoOneBlockFound,
-    InvalidBlock(String),
+    /// 'End' at the position closes a block which wasn't opened or 'Else' at
+    /// the position closes a block which isn't an 'If' block.
+    UnmatchedEnd {
+        position: usize,
+    },
+    /// Blocks which start at the positions are never closed.
+    UnclosedBlocks {
+        starts: Vec<usize>,
+    },
+    InvalidBytecode(DecodeError),
+    /// Instructions of the block which starts at the position are missing,
+    /// i.e. the matched instructions aren't the whole block.
+    MissingInstructions {
+        position: usize,
+    },
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            DiagnosticKind::UnmatchedEnd => write!(
+                f,
+                "Attempt to close the non-existent block, at the position: {}",
+                self.position
+            ),
+            DiagnosticKind::UnclosedBlock => write!(
+                f,
+                "The block wasn't closed, the start block position: {}",
+                self.position
+            ),
+        }
+    }
 }
 
 impl Error for MatchError {}
@@ -115,7 +300,22 @@ impl Display for MatchError {
             MatchError::NoOneBlockFound => {
                 write!(f, "Input program should contain at least one block")
             }
-            MatchError::InvalidBlock(msg) => write!(f, "Invalid block: {}", msg),
+            MatchError::UnmatchedEnd { position } => write!(
+                f,
+                "Invalid block: Attempt to close the non-existent block, at the position: {}",
+                position
+            ),
+            MatchError::UnclosedBlocks { starts } => write!(
+                f,
+                "Invalid block: Next blocks weren't be closed. The start blocks positions: {:?}",
+                starts
+            ),
+            MatchError::InvalidBytecode(err) => write!(f, "Invalid bytecode: {}", err),
+            MatchError::MissingInstructions { position } => write!(
+                f,
+                "Invalid block: Instructions of the block are missing, the start block position: {}",
+                position
+            ),
         }
     }
 }
@@ -124,9 +324,12 @@ impl Display for MatchError {
 mod tests {
     use crate::find_matches;
     use crate::BlockInfo;
+    use crate::BlockKind;
     use crate::Instruction;
     use crate::Instruction::*;
     use crate::MatchError;
+    use crate::Similarity;
+    use crate::Value::*;
 
     #[test]
     fn no_blocks_found() {
@@ -141,26 +344,28 @@ mod tests {
     #[test]
     fn not_opened_block() {
         let register = default_register();
-        let program = vec![Or, End, Push(1), End];
+        let program = vec![Or, End, Push(Int(1)), End];
 
-        let result = find_matches(&register, &program);
+        let err = find_matches(&register, &program).unwrap_err();
 
+        assert_eq!(MatchError::UnmatchedEnd { position: 1 }, err);
         assert_eq!(
             "Invalid block: Attempt to close the non-existent block, at the position: 1",
-            result.unwrap_err().to_string()
+            err.to_string()
         )
     }
 
     #[test]
     fn not_closed_block() {
         let register = default_register();
-        let program = vec![Begin, Push(2), If, End, If];
+        let program = vec![Begin, Push(Int(2)), If, End, If];
 
-        let result = find_matches(&register, &program);
+        let err = find_matches(&register, &program).unwrap_err();
 
+        assert_eq!(MatchError::UnclosedBlocks { starts: vec![0, 4] }, err);
         assert_eq!(
             "Invalid block: Next blocks weren't be closed. The start blocks positions: [0, 4]",
-            result.unwrap_err().to_string()
+            err.to_string()
         )
     }
 
@@ -171,18 +376,23 @@ mod tests {
         let program = vec![
             Begin,
             If,
-            Push(2),
-            Push(3),
+            Push(Int(2)),
+            Push(Int(3)),
             End,
             If,
             If,
             End,
-            Push(1),
+            Push(Int(1)),
             End,
             End,
         ];
 
-        let expected = vec![matched(1, 2), matched(6, 3), not_matched(5), not_matched(0)];
+        let expected = vec![
+            matched(1, 4, 1, BlockKind::If, 2),
+            matched(6, 7, 2, BlockKind::If, 3),
+            not_matched(5, 9, 1, BlockKind::If),
+            not_matched(0, 10, 0, BlockKind::Begin),
+        ];
         let result = find_matches(&known_blocks, &program);
 
         assert_eq!(expected, result.unwrap());
@@ -197,31 +407,95 @@ mod tests {
         let result = find_matches(&register, &program).unwrap();
 
         assert_eq!(deeps_lvl, result.len() - 1);
-        assert_eq!(&not_matched(0), result.get(deeps_lvl).unwrap());
-        assert_eq!(&matched(deeps_lvl * 2 - 1, 2), result.get(0).unwrap());
+        assert_eq!(
+            &not_matched(0, deeps_lvl * 4 + 1, 0, BlockKind::Begin),
+            result.get(deeps_lvl).unwrap()
+        );
+        assert_eq!(
+            &matched(
+                deeps_lvl * 2 - 1,
+                deeps_lvl * 2 + 2,
+                deeps_lvl,
+                BlockKind::If,
+                2
+            ),
+            result.first().unwrap()
+        );
     }
 
-    fn matched(block_start_idx: usize, registry_idx: usize) -> BlockInfo {
+    #[test]
+    fn block_info_accessors() {
+        let register = default_register();
+        let program = vec![Begin, If, Push(Int(2)), Push(Int(3)), End, End];
+
+        let result = find_matches(&register, &program).unwrap();
+
+        let inner = &result[0];
+        assert_eq!(1, inner.block_start_idx());
+        assert_eq!(4, inner.block_end_idx());
+        assert_eq!(4, inner.block_len());
+        assert_eq!(1, inner.depth());
+        assert_eq!(BlockKind::If, inner.kind());
+        assert_eq!(&program[1..5], inner.block(&program));
+        assert_eq!(Some(2), inner.registry_idx());
+        assert_eq!(Some(Similarity::EXACT), inner.similarity());
+        assert!(inner.captures().is_empty() && inner.holes().is_empty());
+        let outer = &result[1];
+        assert_eq!(5, outer.block_end_idx());
+        assert_eq!(0, outer.depth());
+        assert_eq!(BlockKind::Begin, outer.kind());
+        assert_eq!(None, outer.similarity());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_support() {
+        fn assert_serde<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+
+        assert_serde::<Instruction>();
+        assert_serde::<BlockInfo>();
+        assert_serde::<MatchError>();
+    }
+
+    pub(crate) fn matched(
+        block_start_idx: usize,
+        block_end_idx: usize,
+        depth: usize,
+        kind: BlockKind,
+        registry_idx: usize,
+    ) -> BlockInfo {
         BlockInfo {
-            block_start_idx,
             registry_idx: Some(registry_idx),
+            similarity: Some(Similarity::EXACT),
+            ..not_matched(block_start_idx, block_end_idx, depth, kind)
         }
     }
 
-    fn not_matched(block_start_idx: usize) -> BlockInfo {
+    pub(crate) fn not_matched(
+        block_start_idx: usize,
+        block_end_idx: usize,
+        depth: usize,
+        kind: BlockKind,
+    ) -> BlockInfo {
         BlockInfo {
             block_start_idx,
+            block_end_idx,
+            depth,
+            kind,
             registry_idx: None,
+            captures: vec![],
+            holes: vec![],
+            similarity: None,
         }
     }
 
     fn default_register() -> Vec<&'static [Instruction]> {
-        let mut known_blocks: Vec<&'static [Instruction]> = Vec::new();
-        known_blocks.push(&[Begin, Push(1), End]);
-        known_blocks.push(&[If, Push(2), Not, Push(3), End]);
-        known_blocks.push(&[If, Push(2), Push(3), End]);
-        known_blocks.push(&[If, End]);
-        known_blocks
+        vec![
+            &[Begin, Push(Int(1)), End],
+            &[If, Push(Int(2)), Not, Push(Int(3)), End],
+            &[If, Push(Int(2)), Push(Int(3)), End],
+            &[If, End],
+        ]
     }
 
     /// Creates a program with specified deeps level.
@@ -229,10 +503,10 @@ mod tests {
         let mut program = vec![Begin];
         for _idx in 0..deeps_lvl {
             program.push(If);
-            program.push(Push(2));
+            program.push(Push(Int(2)));
         }
         for _idx in 0..deeps_lvl {
-            program.push(Push(3));
+            program.push(Push(Int(3)));
             program.push(End);
         }
         program.push(End);
@@ -0,0 +1,428 @@
+//!
+//! A command-line tool which matches program files with a registry file.
+//!
+//! Program files are either bytecode (recognized by the magic bytes) or text
+//! in the assembly form. Registry files are in the registry file format.
+//!
+//! Exit codes: 0 - all programs are matched, 1 - invalid arguments, 2 - an I/O
+//! or parse failure, 3 - a program is empty, 4 - a program contains an invalid
+//! block. A program without blocks isn't a failure. If a few programs fail, the
+//! code of the first failure is returned.
+//!
+
+use std::env;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::process;
+
+use block_matcher::BlockInfo;
+use block_matcher::BlockRegistry;
+use block_matcher::Instruction;
+use block_matcher::MatchError;
+use block_matcher::RegistryFile;
+
+const USAGE: &str = "Usage: block-matcher [--json] [--fuzzy <max-distance>] [--canonical] [--lenient] <registry-file> <program-file>...";
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_IO_OR_PARSE: i32 = 2;
+const EXIT_NO_BLOCKS: i32 = 3;
+const EXIT_INVALID_BLOCK: i32 = 4;
+
+/// Parsed command-line arguments.
+#[derive(Debug, PartialEq)]
+struct Args {
+    json: bool,
+    max_distance: Option<usize>,
+    canonical: bool,
+    lenient: bool,
+    registry: String,
+    programs: Vec<String>,
+}
+
+/// A failure of reading or matching a single file.
+#[derive(Debug)]
+enum Failure {
+    Io(io::Error),
+    Parse(String),
+    Match(MatchError),
+}
+
+fn main() {
+    process::exit(run(env::args().skip(1).collect(), &mut io::stdout()));
+}
+
+/// Runs the tool with the arguments and writes the report into the output,
+/// returns the exit code.
+fn run(args: Vec<String>, output: &mut dyn Write) -> i32 {
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}\n{}", msg, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let file = match read_registry(&args.registry) {
+        Ok(file) => file,
+        Err(failure) => {
+            eprintln!("{}: {}", args.registry, failure);
+            return failure.exit_code();
+        }
+    };
+    let mut registry = file.to_registry();
+    if let Some(max_distance) = args.max_distance {
+        registry = registry.with_fuzzy_matching(max_distance);
+    }
+    if args.canonical {
+        registry = registry.with_canonical_matching();
+    }
+
+    let mut exit_code = 0;
+    let reports: Vec<_> = args
+        .programs
+        .iter()
+        .map(|path| {
+            let result = match_file(&registry, path, args.lenient);
+            if let Err(failure) = &result {
+                eprintln!("{}: {}", path, failure);
+                if exit_code == 0 {
+                    exit_code = failure.exit_code();
+                }
+            }
+            (path.as_str(), result)
+        })
+        .collect();
+
+    let written = if args.json {
+        writeln!(output, "{}", json_report(&file, &reports))
+    } else {
+        write!(output, "{}", table_report(&file, &reports))
+    };
+    if let Err(err) = written {
+        eprintln!("{}", err);
+        return EXIT_IO_OR_PARSE;
+    }
+
+    exit_code
+}
+
+fn parse_args(args: Vec<String>) -> Result<Args, String> {
+    let mut json = false;
+    let mut max_distance = None;
+    let mut canonical = false;
+    let mut lenient = false;
+    let mut files = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--canonical" => canonical = true,
+            "--lenient" => lenient = true,
+            "--fuzzy" => {
+                let value = args.next().ok_or("Missing value of --fuzzy")?;
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value of --fuzzy: {}", value))?;
+                max_distance = Some(value);
+            }
+            _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+
+    if files.len() < 2 {
+        return Err("Expected a registry file and at least one program file".to_string());
+    }
+    let programs = files.split_off(1);
+
+    Ok(Args {
+        json,
+        max_distance,
+        canonical,
+        lenient,
+        registry: files.remove(0),
+        programs,
+    })
+}
+
+fn read_registry(path: &str) -> Result<RegistryFile, Failure> {
+    let text = fs::read_to_string(path).map_err(Failure::Io)?;
+    block_matcher::parse_registry(&text).map_err(|err| Failure::Parse(err.to_string()))
+}
+
+/// Reads the program from a bytecode or text file.
+fn read_program(path: &str) -> Result<Vec<Instruction>, Failure> {
+    let bytes = fs::read(path).map_err(Failure::Io)?;
+    if bytes.starts_with(block_matcher::MAGIC) {
+        return block_matcher::decode(&bytes).map_err(|err| Failure::Parse(err.to_string()));
+    }
+    let text = String::from_utf8(bytes).map_err(|err| Failure::Parse(err.to_string()))?;
+    block_matcher::parse_program(&text).map_err(|err| Failure::Parse(err.to_string()))
+}
+
+/// Matches the program file, in the lenient mode diagnostics are printed to
+/// stderr and aren't failures.
+fn match_file(
+    registry: &BlockRegistry,
+    path: &str,
+    lenient: bool,
+) -> Result<Vec<BlockInfo>, Failure> {
+    let program = read_program(path)?;
+    if !lenient {
+        return registry.find_matches(&program).map_err(Failure::Match);
+    }
+    let matches = registry
+        .find_matches_lenient(&program)
+        .map_err(Failure::Match)?;
+    for diagnostic in &matches.diagnostics {
+        eprintln!("{}: warning: {}", path, diagnostic);
+    }
+    Ok(matches.blocks)
+}
+
+/// Returns the registry entry name of the block, if the block is known.
+fn entry_name<'a>(file: &'a RegistryFile, info: &BlockInfo) -> Option<&'a str> {
+    info.registry_idx()
+        .map(|idx| file.entries[idx].name.as_str())
+}
+
+fn table_report(
+    file: &RegistryFile,
+    reports: &[(&str, Result<Vec<BlockInfo>, Failure>)],
+) -> String {
+    let mut table = String::new();
+
+    for (path, result) in reports {
+        table.push_str(&format!("{}\n", path));
+        match result {
+            Ok(matches) => {
+                table.push_str(&format!("  {:<8}{:<10}{}\n", "start", "registry", "name"));
+                for info in matches {
+                    let registry_idx = info
+                        .registry_idx()
+                        .map_or("-".to_string(), |idx| idx.to_string());
+                    let name = entry_name(file, info).unwrap_or("-");
+                    table.push_str(&format!(
+                        "  {:<8}{:<10}{}\n",
+                        info.block_start_idx(),
+                        registry_idx,
+                        name
+                    ));
+                }
+            }
+            Err(failure) => table.push_str(&format!("  error: {}\n", failure)),
+        }
+    }
+
+    table
+}
+
+fn json_report(file: &RegistryFile, reports: &[(&str, Result<Vec<BlockInfo>, Failure>)]) -> String {
+    let programs: Vec<String> = reports
+        .iter()
+        .map(|(path, result)| match result {
+            Ok(matches) => {
+                let matches: Vec<String> = matches
+                    .iter()
+                    .map(|info| {
+                        let optional = |value: Option<String>| value.unwrap_or("null".to_string());
+                        format!(
+                            "{{\"start\":{},\"registry_idx\":{},\"name\":{}}}",
+                            info.block_start_idx(),
+                            optional(info.registry_idx().map(|idx| idx.to_string())),
+                            optional(entry_name(file, info).map(json_string)),
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"program\":{},\"matches\":[{}]}}",
+                    json_string(path),
+                    matches.join(",")
+                )
+            }
+            Err(failure) => format!(
+                "{{\"program\":{},\"error\":{}}}",
+                json_string(path),
+                json_string(&failure.to_string())
+            ),
+        })
+        .collect();
+
+    format!("[{}]", programs.join(","))
+}
+
+/// Returns the string as a JSON string literal.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            _ if ch.is_control() => json.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => json.push(ch),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Io(_) | Failure::Parse(_) => EXIT_IO_OR_PARSE,
+            Failure::Match(MatchError::NoOneBlockFound) => EXIT_NO_BLOCKS,
+            Failure::Match(MatchError::UnmatchedEnd { .. })
+            | Failure::Match(MatchError::UnclosedBlocks { .. })
+            | Failure::Match(MatchError::MissingInstructions { .. }) => EXIT_INVALID_BLOCK,
+            Failure::Match(MatchError::InvalidBytecode(_)) => EXIT_IO_OR_PARSE,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Failure::Io(err) => write!(f, "{}", err),
+            Failure::Parse(msg) => write!(f, "{}", msg),
+            Failure::Match(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use block_matcher::encode;
+    use block_matcher::Instruction::*;
+    use block_matcher::Value::*;
+
+    use crate::json_string;
+    use crate::parse_args;
+    use crate::run;
+    use crate::Args;
+
+    #[test]
+    fn arguments() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()).collect());
+
+        assert_eq!(
+            Ok(Args {
+                json: true,
+                max_distance: Some(2),
+                canonical: false,
+                lenient: false,
+                registry: "known.reg".into(),
+                programs: vec!["a.asm".into(), "b.bin".into()],
+            }),
+            args(&["known.reg", "--json", "a.asm", "--fuzzy", "2", "b.bin"])
+        );
+        assert!(args(&["known.reg"]).is_err());
+        assert!(args(&["--fuzzy", "x", "known.reg", "a.asm"]).is_err());
+        assert!(args(&["--verbose", "known.reg", "a.asm"]).is_err());
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(r#""a \"b\"\\c\n\u0001""#, json_string("a \"b\"\\c\n\u{1}"));
+    }
+
+    #[test]
+    fn table_output() {
+        let registry = temp_file("table.reg", REGISTRY.as_bytes());
+        let text = temp_file("table.asm", b"begin if push 2 end push 1 end");
+        let bytecode = temp_file("table.bin", &encode(&[Begin, Push(Int(1)), End]));
+
+        let (exit_code, output) = run_with(&[&registry, &text, &bytecode]);
+
+        assert_eq!(0, exit_code);
+        assert_eq!(
+            format!(
+                "{}\n  start   registry  name\n  1       0         if-two\n  0       -         -\n\
+                 {}\n  start   registry  name\n  0       1         begin-one\n",
+                text, bytecode
+            ),
+            output
+        );
+    }
+
+    #[test]
+    fn json_output() {
+        let registry = temp_file("json.reg", REGISTRY.as_bytes());
+        let program = temp_file("json.asm", b"push 1\nbegin push 1 end");
+        let missing = temp_file("json-missing.asm", b"");
+        fs::remove_file(&missing).unwrap();
+
+        let (exit_code, output) = run_with(&["--json", &registry, &program, &missing]);
+
+        assert_eq!(2, exit_code);
+        let (prefix, error) = output.split_at(output.find(",\"error\"").unwrap());
+        assert_eq!(
+            format!(
+                "[{{\"program\":{},\"matches\":[{{\"start\":1,\"registry_idx\":1,\"name\":\"begin-one\"}}]}},\
+                 {{\"program\":{}",
+                json_string(&program),
+                json_string(&missing)
+            ),
+            prefix
+        );
+        assert!(error.ends_with("\"}]\n"));
+    }
+
+    #[test]
+    fn exit_codes() {
+        let registry = temp_file("codes.reg", REGISTRY.as_bytes());
+        let no_blocks = temp_file("codes-no-blocks.asm", b"push 1");
+        let empty = temp_file("codes-empty.asm", b"");
+        let unmatched = temp_file("codes-unmatched.asm", b"begin end end");
+        let unclosed = temp_file("codes-unclosed.asm", b"begin if end");
+        let invalid = temp_file("codes-invalid.asm", b"begin jump end");
+        let truncated = temp_file("codes-truncated.bin", b"BMBC\x03\x01");
+        let bad_registry = temp_file("codes-bad.reg", b"registry 9");
+
+        assert_eq!(0, run_with(&[&registry, &no_blocks]).0);
+        assert_eq!(1, run_with(&[&registry]).0);
+        assert_eq!(2, run_with(&[&registry, &invalid]).0);
+        assert_eq!(2, run_with(&[&registry, &truncated]).0);
+        assert_eq!(2, run_with(&[&bad_registry, &no_blocks]).0);
+        assert_eq!(2, run_with(&[&registry, "missing.asm"]).0);
+        assert_eq!(3, run_with(&[&registry, &empty]).0);
+        assert_eq!(4, run_with(&[&registry, &unmatched]).0);
+        assert_eq!(4, run_with(&[&registry, &unclosed]).0);
+        assert_eq!(0, run_with(&["--lenient", &registry, &unclosed]).0);
+        assert_eq!(4, run_with(&[&registry, &unclosed, &empty]).0);
+    }
+
+    const REGISTRY: &str =
+        "registry 1\n\nentry if-two\nif push 2 end\n\nentry begin-one\nbegin push 1 end\n";
+
+    /// Runs the tool with the arguments, returns the exit code and the output.
+    fn run_with(args: &[&str]) -> (i32, String) {
+        let mut output = Vec::new();
+        let exit_code = run(
+            args.iter().map(|arg| arg.to_string()).collect(),
+            &mut output,
+        );
+        (exit_code, String::from_utf8(output).unwrap())
+    }
+
+    /// Writes the file into the temporary directory, returns its path.
+    fn temp_file(name: &str, contents: &[u8]) -> String {
+        let dir = env::temp_dir().join(format!("block-matcher-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+}
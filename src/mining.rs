@@ -0,0 +1,293 @@
+//!
+//! Mining of new registry entries from blocks which the registry doesn't know
+//! yet. Unmatched blocks of a corpus of programs are aggregated and ranked by
+//! estimated savings, i.e. by the number of occurrences times the block length.
+//!
+
+use std::collections::HashMap;
+
+use crate::fingerprint::ClosedBlock;
+use crate::fingerprint::Fingerprint;
+use crate::BlockPosition;
+use crate::BlockRegistry;
+use crate::Instruction;
+use crate::MatchError;
+use crate::RegistryEntry;
+use crate::RegistryFile;
+use crate::Token;
+
+/// A prefix of names of mined registry entries.
+const CANDIDATE_NAME: &str = "candidate";
+
+/// Aggregates unmatched blocks of programs matched with the registry.
+#[derive(Debug, Clone)]
+pub struct RegistryMiner<'r> {
+    registry: &'r BlockRegistry,
+    /// The least number of occurrences of a candidate
+    min_count: usize,
+    /// The least number of instructions of a candidate, including 'End'
+    min_len: usize,
+    /// Programs which contain the first occurrences of unmatched blocks
+    programs: Vec<Vec<Instruction>>,
+    /// First occurrences of unmatched blocks with their number of
+    /// occurrences, in the order of first occurrences
+    blocks: Vec<(BlockPosition, usize)>,
+    /// Indices of unmatched blocks by their fingerprints
+    index: HashMap<Fingerprint, Vec<usize>>,
+}
+
+/// A block which is suggested as a new registry entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub block: Vec<Instruction>,
+    /// The number of occurrences of the block in all programs
+    pub count: usize,
+    /// The number of instructions in all occurrences of the block
+    pub savings: usize,
+}
+
+impl<'r> RegistryMiner<'r> {
+    /// Creates the miner of blocks which are unknown to the registry. By
+    /// default a candidate should occur at least twice and should contain at
+    /// least one instruction besides the opening instruction and 'End'.
+    pub fn new(registry: &'r BlockRegistry) -> Self {
+        RegistryMiner {
+            registry,
+            min_count: 2,
+            min_len: 3,
+            programs: Vec::new(),
+            blocks: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// Sets the least number of occurrences of a candidate.
+    pub fn with_min_count(mut self, min_count: usize) -> Self {
+        self.min_count = min_count;
+        self
+    }
+
+    /// Sets the least number of instructions of a candidate, including the
+    /// opening instruction and 'End'.
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+
+    /// Matches the program with the registry and aggregates its unmatched
+    /// blocks, nested blocks are aggregated separately. A block matched by a
+    /// fuzzy registry isn't unmatched.
+    pub fn add_program(&mut self, program: &[Instruction]) -> Result<(), MatchError> {
+        let registry = self.registry;
+        let mut unmatched: Vec<ClosedBlock> = Vec::new();
+        registry.walk(program, 0..program.len(), |step| {
+            for closed in step.into_closed() {
+                let info = registry.match_block(&closed, closed.slice(program))?;
+                if info.registry_idx().is_none() {
+                    unmatched.push(closed);
+                }
+            }
+            Ok(())
+        })?;
+
+        // the program is kept only if it contains a new block, so instructions
+        // of blocks are compared only if their fingerprints are the same
+        let RegistryMiner {
+            programs,
+            blocks,
+            index,
+            ..
+        } = self;
+        let program_idx = programs.len();
+        let mut is_kept = false;
+        for closed in unmatched {
+            let block = closed.slice(program);
+            let candidates = index.entry(closed.fingerprint).or_default();
+            let found = candidates.iter().copied().find(|&block_idx| {
+                let (position, _) = blocks[block_idx];
+                let known = programs
+                    .get(position.program_idx)
+                    .map_or(program, Vec::as_slice);
+                &known[position.start..=position.end] == block
+            });
+            match found {
+                Some(block_idx) => blocks[block_idx].1 += 1,
+                None => {
+                    candidates.push(blocks.len());
+                    let position = BlockPosition {
+                        program_idx,
+                        start: closed.start,
+                        end: closed.end,
+                    };
+                    blocks.push((position, 1));
+                    is_kept = true;
+                }
+            }
+        }
+        if is_kept {
+            programs.push(program.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Returns candidates ranked by their savings, candidates with equal
+    /// savings are in the order of their first occurrence.
+    pub fn candidates(&self) -> Vec<Candidate> {
+        let mut ranked: Vec<_> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(order, &(position, count))| {
+                let program = &self.programs[position.program_idx];
+                let block = &program[position.start..=position.end];
+                if count < self.min_count || block.len() < self.min_len {
+                    return None;
+                }
+                let candidate = Candidate {
+                    block: block.to_vec(),
+                    count,
+                    savings: count * block.len(),
+                };
+                Some((candidate, order))
+            })
+            .collect();
+        ranked.sort_by(|(left, left_order), (right, right_order)| {
+            right
+                .savings
+                .cmp(&left.savings)
+                .then(left_order.cmp(right_order))
+        });
+        ranked.into_iter().map(|(candidate, _)| candidate).collect()
+    }
+
+    /// Returns the ranked candidates as entries of a registry file. Entries are
+    /// named by their rank, the description tells the estimated savings.
+    pub fn registry_file(&self) -> RegistryFile {
+        let entries = self
+            .candidates()
+            .into_iter()
+            .enumerate()
+            .map(|(idx, candidate)| RegistryEntry {
+                name: format!("{}-{}", CANDIDATE_NAME, idx + 1),
+                description: Some(format!(
+                    "Occurs {} times, {} instructions in total",
+                    candidate.count, candidate.savings
+                )),
+                pattern: candidate.block.into_iter().map(Token::from).collect(),
+            })
+            .collect();
+        RegistryFile { entries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mining::Candidate;
+    use crate::mining::RegistryMiner;
+    use crate::parse_registry;
+    use crate::BlockRegistry;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn candidates_are_ranked_by_savings() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(1)), End]]);
+        let mut miner = RegistryMiner::new(&registry);
+
+        miner
+            .add_program(&[
+                Begin,
+                If,
+                Push(Int(2)),
+                End,
+                If,
+                End,
+                If,
+                Push(Int(1)),
+                End,
+                End,
+            ])
+            .unwrap();
+        miner
+            .add_program(&[If, Push(Int(2)), End, Begin, Push(Int(3)), Not, End])
+            .unwrap();
+        miner
+            .add_program(&[Begin, Push(Int(3)), Not, End, If, End])
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                Candidate {
+                    block: vec![Begin, Push(Int(3)), Not, End],
+                    count: 2,
+                    savings: 8,
+                },
+                Candidate {
+                    block: vec![If, Push(Int(2)), End],
+                    count: 2,
+                    savings: 6,
+                },
+            ],
+            miner.candidates()
+        );
+        assert_eq!(
+            4,
+            miner.with_min_len(2).with_min_count(1).candidates().len()
+        );
+    }
+
+    #[test]
+    fn nested_blocks_and_failed_programs() {
+        let registry = BlockRegistry::default();
+        let mut miner = RegistryMiner::new(&registry).with_min_len(2);
+        let nested = [Begin, Loop, If, End, End, End];
+
+        miner.add_program(&nested).unwrap();
+        miner
+            .add_program(&[Loop, If, End, End, Begin, If])
+            .unwrap_err();
+        miner.add_program(&[Pop, Loop, If, End, End]).unwrap();
+
+        assert_eq!(
+            vec![
+                Candidate {
+                    block: vec![Loop, If, End, End],
+                    count: 2,
+                    savings: 8,
+                },
+                Candidate {
+                    block: vec![If, End],
+                    count: 2,
+                    savings: 4,
+                },
+            ],
+            miner.candidates()
+        );
+        let blocks: Vec<_> = miner
+            .with_min_count(1)
+            .candidates()
+            .into_iter()
+            .map(|candidate| candidate.block)
+            .collect();
+        assert_eq!(
+            vec![vec![Loop, If, End, End], nested.to_vec(), vec![If, End]],
+            blocks
+        );
+    }
+
+    #[test]
+    fn candidates_as_registry_file() {
+        let registry = BlockRegistry::default();
+        let mut miner = RegistryMiner::new(&registry);
+        miner.add_program(&[If, Push(Int(2)), End]).unwrap();
+        miner.add_program(&[If, Push(Int(2)), End]).unwrap();
+
+        let text = miner.registry_file().to_string();
+
+        assert_eq!(
+            "registry 1\n\nentry candidate-1\ndescription Occurs 2 times, 6 instructions in total\nif\n  push 2\nend\n",
+            text
+        );
+        assert_eq!(miner.registry_file(), parse_registry(&text).unwrap());
+    }
+}
@@ -0,0 +1,200 @@
+//!
+//! Occurrences of registry blocks anywhere in a program, not only at block
+//! boundaries. All registry sequences are compiled into a single Aho-Corasick
+//! automaton, so the program is scanned once regardless of the registry size.
+//!
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use crate::Instruction;
+
+/// An occurrence of a registry sequence in a program.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq, Hash)]
+pub struct Occurrence {
+    /// An index of this sequence in the registry
+    pub registry_idx: usize,
+    /// An index of the first instruction of the occurrence in the program
+    pub start: usize,
+    /// An index of the last instruction of the occurrence in the program
+    pub end: usize,
+}
+
+/// The Aho-Corasick automaton of registry sequences.
+#[derive(Debug, Clone)]
+pub(crate) struct Automaton {
+    /// All states, the root is the first one
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    next: HashMap<Instruction, usize>,
+    /// The state of the longest proper suffix of this state which is a prefix
+    /// of some sequence
+    fail: usize,
+    /// A registry index and a length of the sequence which ends at this state
+    output: Option<(usize, usize)>,
+    /// The nearest state by fail links which has an output
+    dict: Option<usize>,
+}
+
+const ROOT: usize = 0;
+
+impl Automaton {
+    /// Builds the automaton of the specified sequences with their registry
+    /// indices. Empty sequences are ignored, if the same sequence is
+    /// specified twice, the last one takes precedence.
+    pub fn new<'a, I>(sequences: I) -> Self
+    where
+        I: IntoIterator<Item = (usize, &'a [Instruction])>,
+    {
+        let mut automaton = Automaton {
+            nodes: vec![Node::default()],
+        };
+        for (registry_idx, sequence) in sequences {
+            if !sequence.is_empty() {
+                automaton.insert(registry_idx, sequence);
+            }
+        }
+        automaton.link();
+        automaton
+    }
+
+    fn insert(&mut self, registry_idx: usize, sequence: &[Instruction]) {
+        let mut state = ROOT;
+        for instruction in sequence {
+            state = match self.nodes[state].next.get(instruction) {
+                Some(&next) => next,
+                None => {
+                    let next = self.nodes.len();
+                    self.nodes.push(Node::default());
+                    self.nodes[state].next.insert(instruction.clone(), next);
+                    next
+                }
+            };
+        }
+        self.nodes[state].output = Some((registry_idx, sequence.len()));
+    }
+
+    /// Computes fail and dictionary links in breadth-first order.
+    fn link(&mut self) {
+        let mut queue: VecDeque<usize> = self.nodes[ROOT].next.values().copied().collect();
+
+        while let Some(state) = queue.pop_front() {
+            let children: Vec<_> = self.nodes[state]
+                .next
+                .iter()
+                .map(|(instruction, &child)| (instruction.clone(), child))
+                .collect();
+            for (instruction, child) in children {
+                let mut fail = self.nodes[state].fail;
+                let child_fail = loop {
+                    if let Some(&next) = self.nodes[fail].next.get(&instruction) {
+                        break next;
+                    }
+                    if fail == ROOT {
+                        break ROOT;
+                    }
+                    fail = self.nodes[fail].fail;
+                };
+                self.nodes[child].fail = child_fail;
+                self.nodes[child].dict = if self.nodes[child_fail].output.is_some() {
+                    Some(child_fail)
+                } else {
+                    self.nodes[child_fail].dict
+                };
+                queue.push_back(child);
+            }
+        }
+    }
+
+    /// Returns all occurrences of the sequences in the program, including
+    /// overlapping ones, ordered by their ends and then by their starts.
+    pub fn find(&self, program: &[Instruction]) -> Vec<Occurrence> {
+        let mut occurrences = Vec::new();
+        let mut state = ROOT;
+
+        for (position, instruction) in program.iter().enumerate() {
+            state = loop {
+                if let Some(&next) = self.nodes[state].next.get(instruction) {
+                    break next;
+                }
+                if state == ROOT {
+                    break ROOT;
+                }
+                state = self.nodes[state].fail;
+            };
+
+            let mut output_state = if self.nodes[state].output.is_some() {
+                Some(state)
+            } else {
+                self.nodes[state].dict
+            };
+            while let Some(found) = output_state {
+                if let Some((registry_idx, len)) = self.nodes[found].output {
+                    occurrences.push(Occurrence {
+                        registry_idx,
+                        start: position + 1 - len,
+                        end: position,
+                    });
+                }
+                output_state = self.nodes[found].dict;
+            }
+        }
+
+        occurrences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::occurrence::Automaton;
+    use crate::occurrence::Occurrence;
+    use crate::Instruction;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn overlapping_occurrences() {
+        let sequences: Vec<&[Instruction]> = vec![
+            &[Push(Int(2)), Not, Push(Int(3))],
+            &[Not, Push(Int(3))],
+            &[Push(Int(3)), Push(Int(2))],
+            &[Push(Int(2))],
+        ];
+        let automaton = Automaton::new(sequences.into_iter().enumerate());
+
+        let found = automaton.find(&[Begin, Push(Int(2)), Not, Push(Int(3)), Push(Int(2)), End]);
+
+        assert_eq!(
+            vec![
+                occurrence(3, 1, 1),
+                occurrence(0, 1, 3),
+                occurrence(1, 2, 3),
+                occurrence(2, 3, 4),
+                occurrence(3, 4, 4),
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn repeated_sequence_and_fail_links() {
+        let sequences: Vec<&[Instruction]> = vec![&[Not, Not, Or], &[Not, Or], &[Not, Or]];
+        let automaton = Automaton::new(sequences.into_iter().enumerate());
+
+        let found = automaton.find(&[Not, Not, Not, Or, And]);
+
+        assert_eq!(vec![occurrence(0, 1, 3), occurrence(2, 2, 3)], found);
+        assert!(automaton.find(&[Or, Not]).is_empty());
+    }
+
+    fn occurrence(registry_idx: usize, start: usize, end: usize) -> Occurrence {
+        Occurrence {
+            registry_idx,
+            start,
+            end,
+        }
+    }
+}
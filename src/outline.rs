@@ -0,0 +1,234 @@
+//!
+//! Outlining of matched blocks into calls of registry blocks and the inverse
+//! expansion. Only blocks which are exactly the same as their registry blocks
+//! are outlined, so expanding calls reproduces the original program.
+//!
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::BlockInfo;
+use crate::BlockKind;
+use crate::BlockRegistry;
+use crate::Instruction;
+
+/// An error occurred while expanding calls of a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandError {
+    /// An index of the failed 'Call' in the program
+    pub position: usize,
+    /// The called registry index, which isn't a plain block of the registry
+    pub registry_idx: usize,
+}
+
+/// Replaces each matched block of the program by 'Call' of its registry
+/// index. `matches` are blocks of the program, e.g. [`BlockRegistry::find_matches`]
+/// returns them.
+///
+/// A block is outlined only if it is exactly the same as its registry block,
+/// i.e. blocks matched with patterns, canonically or fuzzily are kept as is.
+/// A block nested into an outlined block is a part of the call. Arms of 'If'
+/// are never outlined, since they share their 'Else' with each other, but the
+/// whole 'If' block with both arms is.
+///
+/// Calls which are already in the program are expanded by [`expand_calls`] as
+/// well, so the expansion reproduces only a program without calls.
+///
+/// # Panics
+///
+/// Panics if a matched block is out of the program, i.e. it isn't the matched
+/// program.
+pub fn outline_blocks(
+    registry: &BlockRegistry,
+    program: &[Instruction],
+    matches: &[BlockInfo],
+) -> Vec<Instruction> {
+    let mut outlined: Vec<(usize, usize, usize)> = matches
+        .iter()
+        .filter(|info| info.block_end_idx() < program.len() && !is_arm(info, program))
+        .filter_map(|info| {
+            let registry_idx = info.registry_idx()?;
+            let known = registry.block(registry_idx)?;
+            (known == info.block(program)).then(|| {
+                let (start, end) = (info.block_start_idx(), info.block_end_idx());
+                (start, end, registry_idx)
+            })
+        })
+        .collect();
+    outlined.sort_unstable();
+
+    let mut result = Vec::with_capacity(program.len());
+    let mut position = 0;
+    for (start, end, registry_idx) in outlined {
+        if start < position {
+            continue; // nested into the previous outlined block
+        }
+        result.extend_from_slice(&program[position..start]);
+        result.push(Instruction::Call(registry_idx));
+        position = end + 1;
+    }
+    result.extend_from_slice(&program[position..]);
+
+    result
+}
+
+/// Replaces each 'Call' of the program by instructions of the called registry
+/// block, it is the inverse of [`outline_blocks`]. Calls inside registry
+/// blocks are kept as is.
+///
+/// Fails on the first call of a registry index which isn't a plain block, i.e.
+/// it is a pattern or it is out of the registry.
+pub fn expand_calls(
+    registry: &BlockRegistry,
+    program: &[Instruction],
+) -> Result<Vec<Instruction>, ExpandError> {
+    let mut result = Vec::with_capacity(program.len());
+
+    for (position, instruction) in program.iter().enumerate() {
+        match instruction {
+            Instruction::Call(registry_idx) => {
+                let block = registry.block(*registry_idx).ok_or(ExpandError {
+                    position,
+                    registry_idx: *registry_idx,
+                })?;
+                result.extend_from_slice(block);
+            }
+            _ => result.push(instruction.clone()),
+        }
+    }
+
+    Ok(result)
+}
+
+/// Returns true if the block is an arm of 'If', i.e. it starts or ends with
+/// 'Else'.
+fn is_arm(info: &BlockInfo, program: &[Instruction]) -> bool {
+    info.kind() == BlockKind::Else || program[info.block_end_idx()] == Instruction::Else
+}
+
+impl Error for ExpandError {}
+
+impl Display for ExpandError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Attempt to call the unknown block {}, at the position: {}",
+            self.registry_idx, self.position
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expand_calls;
+    use crate::outline_blocks;
+    use crate::BlockRegistry;
+    use crate::ExpandError;
+    use crate::Instruction::*;
+    use crate::Operand::Any;
+    use crate::Token;
+    use crate::Token::Ins;
+    use crate::Value::*;
+
+    #[test]
+    fn outline_and_expand_round_trip() {
+        let registry = BlockRegistry::new(&[
+            &[If, Push(Int(2)), End],
+            &[Begin, If, Push(Int(2)), End, End],
+        ]);
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(2)),
+            End,
+            End,
+            If,
+            Push(Int(2)),
+            End,
+            Begin,
+            Not,
+            End,
+        ];
+        let matches = registry.find_matches(&program).unwrap();
+
+        let outlined = outline_blocks(&registry, &program, &matches);
+
+        assert_eq!(vec![Call(1), Call(0), Begin, Not, End], outlined);
+        assert_eq!(Ok(program), expand_calls(&registry, &outlined));
+    }
+
+    #[test]
+    fn only_exact_blocks_are_outlined() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(If), Token::Push(Any), Ins(End)],
+            vec![Ins(Begin), Ins(Push(Int(1))), Ins(End)],
+            vec![Ins(If), Ins(Push(Int(1))), Ins(Else)],
+            vec![Ins(Else), Ins(Not), Ins(End)],
+        ])
+        .with_fuzzy_matching(1);
+        let program = vec![
+            Loop,
+            If,
+            Push(Int(5)),
+            End,
+            Begin,
+            Push(Int(1)),
+            Not,
+            End,
+            If,
+            Push(Int(1)),
+            Else,
+            Not,
+            End,
+            End,
+        ];
+        let matches = registry.find_matches(&program).unwrap();
+
+        let outlined = outline_blocks(&registry, &program, &matches);
+
+        let known = matches.iter().filter(|info| info.registry_idx().is_some());
+        assert_eq!(4, known.count());
+        assert_eq!(program, outlined);
+    }
+
+    #[test]
+    fn whole_if_block_is_outlined() {
+        let registry = BlockRegistry::new(&[
+            &[If, Push(Int(1)), Else],
+            &[If, Push(Int(1)), Else, Not, End],
+        ]);
+        let program = vec![Begin, If, Push(Int(1)), Else, Not, End, End];
+        let matches = registry.find_matches(&program).unwrap();
+
+        let outlined = outline_blocks(&registry, &program, &matches);
+
+        assert_eq!(vec![Begin, Call(1), End], outlined);
+        assert_eq!(Ok(program), expand_calls(&registry, &outlined));
+    }
+
+    #[test]
+    fn unknown_calls() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(Begin), Ins(Call(1)), Ins(End)],
+            vec![Ins(If), Token::Push(Any), Ins(End)],
+        ]);
+
+        assert_eq!(
+            Ok(vec![Begin, Begin, Call(1), End, End]),
+            expand_calls(&registry, &[Begin, Call(0), End])
+        );
+        assert_eq!(
+            Err(ExpandError {
+                position: 1,
+                registry_idx: 1,
+            }),
+            expand_calls(&registry, &[Call(0), Call(1)])
+        );
+        assert_eq!(
+            "Attempt to call the unknown block 2, at the position: 0",
+            expand_calls(&registry, &[Call(2)]).unwrap_err().to_string()
+        );
+    }
+}
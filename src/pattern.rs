@@ -0,0 +1,390 @@
+//!
+//! Patterns of registry entries. A pattern is a sequence of tokens, most
+//! tokens match a single instruction, but unlike a plain instruction they may
+//! constrain an operand instead of requiring an exact value. Hole tokens match
+//! whole nested blocks or sequences of instructions.
+//!
+
+use crate::delimiter::BlockDelimiter;
+use crate::delimiter::Delimiter;
+use crate::Instruction;
+use crate::Value;
+use crate::ValueType;
+use std::ops::Range;
+
+/// A constraint on the operand of the 'Push' instruction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Operand {
+    /// Matches only the specified value.
+    Exact(Value),
+    /// Matches any value.
+    Any,
+    /// Matches any value of the type.
+    Type(ValueType),
+    /// Matches any integer inside the range.
+    Range(Range<i64>),
+    /// Matches any value and captures it by the name. All operands captured
+    /// with the same name must be equal within the block.
+    Capture(String),
+}
+
+/// A single token of a registry pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Token {
+    /// Matches exactly the same instruction.
+    Ins(Instruction),
+    /// Matches the 'Push' instruction with an operand satisfying the constraint.
+    Push(Operand),
+    /// A hole which matches exactly one well-formed nested block.
+    AnyBlock,
+    /// A hole which matches any sequence of instructions (possibly empty) in
+    /// which all nested blocks are closed.
+    AnySequence,
+}
+
+/// Operands captured by a pattern, in order of their first occurrence.
+pub type Captures = Vec<(String, Value)>;
+
+/// Spans of instructions matched by holes of a pattern, in the pattern order.
+/// Each span is a pair of the first position and the position after the last
+/// one, so an empty sequence is reported as an empty span.
+pub type Holes = Vec<(usize, usize)>;
+
+/// Everything bound by a pattern while matching a block.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Bindings {
+    pub captures: Captures,
+    /// Spans relative to the beginning of the matched block
+    pub holes: Holes,
+}
+
+impl From<Instruction> for Token {
+    fn from(instruction: Instruction) -> Self {
+        Token::Ins(instruction)
+    }
+}
+
+impl Token {
+    /// Returns the instruction if this token matches exactly one instruction.
+    pub(crate) fn as_exact(&self) -> Option<Instruction> {
+        match self {
+            Token::Ins(instruction) => Some(instruction.clone()),
+            Token::Push(Operand::Exact(value)) => Some(Instruction::Push(value.clone())),
+            Token::Push(_) | Token::AnyBlock | Token::AnySequence => None,
+        }
+    }
+
+    /// Returns true if this token is a hole, i.e. may match a number of
+    /// instructions.
+    pub(crate) fn is_hole(&self) -> bool {
+        match self {
+            Token::AnyBlock | Token::AnySequence => true,
+            Token::Ins(_) | Token::Push(_) => false,
+        }
+    }
+
+    /// Returns an instruction with the same shape as the instruction matched
+    /// by this token, i.e. the same instruction but possibly another operand.
+    /// Holes have no shape.
+    pub(crate) fn shape(&self) -> Option<Instruction> {
+        match self {
+            Token::Ins(instruction) => Some(instruction.clone()),
+            Token::Push(_) => Some(Instruction::Push(Value::Int(0))),
+            Token::AnyBlock | Token::AnySequence => None,
+        }
+    }
+}
+
+/// Matches the pattern with the block. Returns captured operands and spans of
+/// holes if the block matches the pattern, otherwise returns None.
+///
+/// Holes are matched with backtracking, so a pattern with many
+/// [`Token::AnySequence`] holes may take time exponential to their number.
+pub(crate) fn match_pattern(pattern: &[Token], block: &[Instruction]) -> Option<Bindings> {
+    let mut bindings = Bindings::default();
+    if match_from(pattern, block, 0, &mut bindings) {
+        Some(bindings)
+    } else {
+        None
+    }
+}
+
+/// Matches the rest of the pattern with the rest of the block, which starts
+/// at the specified position.
+fn match_from(
+    pattern: &[Token],
+    block: &[Instruction],
+    pos: usize,
+    bindings: &mut Bindings,
+) -> bool {
+    let (token, rest) = match pattern.split_first() {
+        Some(split) => split,
+        None => return pos == block.len(),
+    };
+
+    match token {
+        Token::AnyBlock => match block_end(block, pos) {
+            Some(end) => bind_hole(rest, block, (pos, end + 1), bindings),
+            None => false,
+        },
+        Token::AnySequence => {
+            let mut depth = 0usize;
+            let mut next = pos;
+            loop {
+                if depth == 0 && bind_hole(rest, block, (pos, next), bindings) {
+                    return true;
+                }
+                // a sequence never closes or splits a block it doesn't open
+                depth = match block.get(next).map(Instruction::delimiter) {
+                    Some(Delimiter::Open) => depth + 1,
+                    Some(Delimiter::Close) if depth > 0 => depth - 1,
+                    Some(Delimiter::Close) | Some(Delimiter::Split) if depth == 0 => return false,
+                    Some(_) => depth,
+                    None => return false,
+                };
+                next += 1;
+            }
+        }
+        Token::Ins(_) | Token::Push(_) => {
+            let captured = bindings.captures.len();
+            let matched = block
+                .get(pos)
+                .is_some_and(|instruction| match_token(token, instruction, &mut bindings.captures));
+            if matched && match_from(rest, block, pos + 1, bindings) {
+                return true;
+            }
+            bindings.captures.truncate(captured);
+            false
+        }
+    }
+}
+
+/// Binds the span to the hole and matches the rest of the pattern, unbinds
+/// the span if the rest doesn't match.
+fn bind_hole(
+    rest: &[Token],
+    block: &[Instruction],
+    span: (usize, usize),
+    bindings: &mut Bindings,
+) -> bool {
+    bindings.holes.push(span);
+    if match_from(rest, block, span.1, bindings) {
+        true
+    } else {
+        bindings.holes.pop();
+        false
+    }
+}
+
+/// Matches a single token with a single instruction, new captures are added
+/// to `captures`.
+fn match_token(token: &Token, instruction: &Instruction, captures: &mut Captures) -> bool {
+    match (token, instruction) {
+        (Token::Ins(expected), _) => expected == instruction,
+        (Token::Push(operand), Instruction::Push(value)) => match operand {
+            Operand::Exact(expected) => expected == value,
+            Operand::Any => true,
+            Operand::Type(value_type) => value.value_type() == *value_type,
+            Operand::Range(range) => match value {
+                Value::Int(value) => range.contains(value),
+                Value::Bool(_) | Value::Symbol(_) => false,
+            },
+            Operand::Capture(name) => {
+                match captures.iter().find(|(captured, _)| captured == name) {
+                    Some((_, captured_value)) => captured_value == value,
+                    None => {
+                        captures.push((name.clone(), value.clone()));
+                        true
+                    }
+                }
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Returns true if the token matches the instruction, regardless of operands
+/// captured by other tokens. Holes never match a single instruction.
+pub(crate) fn token_matches(token: &Token, instruction: &Instruction) -> bool {
+    match token {
+        Token::Ins(_) | Token::Push(_) => match_token(token, instruction, &mut Captures::new()),
+        Token::AnyBlock | Token::AnySequence => false,
+    }
+}
+
+/// Returns the position of 'End' which closes the block opened at the
+/// specified position, or None if there is no block at this position.
+fn block_end(block: &[Instruction], start: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (pos, instruction) in block.iter().enumerate().skip(start) {
+        depth = match instruction.delimiter() {
+            Delimiter::Open => depth + 1,
+            Delimiter::Close if depth > 1 => depth - 1,
+            Delimiter::Close if depth == 1 => return Some(pos),
+            _ if depth == 0 => return None,
+            _ => depth,
+        };
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pattern::match_pattern;
+    use crate::pattern::Bindings;
+    use crate::pattern::Operand::*;
+    use crate::pattern::Token;
+    use crate::Instruction::*;
+    use crate::Value::*;
+    use crate::ValueType;
+
+    #[test]
+    fn wildcard_operands() {
+        let pattern = vec![
+            Token::Ins(If),
+            Token::Push(Any),
+            Token::Push(Range(0..10)),
+            Token::Ins(End),
+        ];
+
+        assert_eq!(
+            Some(Bindings::default()),
+            match_pattern(&pattern, &[If, Push(Int(100)), Push(Int(9)), End])
+        );
+        assert_eq!(
+            None,
+            match_pattern(&pattern, &[If, Push(Int(1)), Push(Int(10)), End])
+        );
+        assert_eq!(None, match_pattern(&pattern, &[If, Push(Int(1)), Not, End]));
+        assert_eq!(None, match_pattern(&pattern, &[If, Push(Int(1)), End]));
+    }
+
+    #[test]
+    fn named_captures_should_be_equal() {
+        let pattern = vec![
+            Token::Ins(If),
+            Token::Push(Capture("x".into())),
+            Token::Push(Capture("y".into())),
+            Token::Push(Capture("x".into())),
+            Token::Ins(End),
+        ];
+
+        assert_eq!(
+            Some(vec![("x".into(), Int(4)), ("y".into(), Int(5))]),
+            match_pattern(
+                &pattern,
+                &[If, Push(Int(4)), Push(Int(5)), Push(Int(4)), End]
+            )
+            .map(|b| b.captures)
+        );
+        assert_eq!(
+            None,
+            match_pattern(
+                &pattern,
+                &[If, Push(Int(4)), Push(Int(5)), Push(Int(5)), End]
+            )
+        );
+    }
+
+    #[test]
+    fn any_block_hole() {
+        let pattern = vec![Token::Ins(If), Token::AnyBlock, Token::Ins(End)];
+
+        assert_eq!(
+            Some(vec![(1, 5)]),
+            match_pattern(&pattern, &[If, Begin, If, End, End, End]).map(|b| b.holes)
+        );
+        assert_eq!(None, match_pattern(&pattern, &[If, End]));
+        assert_eq!(None, match_pattern(&pattern, &[If, Push(Int(1)), End]));
+        assert_eq!(None, match_pattern(&pattern, &[If, If, End, If, End, End]));
+    }
+
+    #[test]
+    fn any_sequence_hole() {
+        let pattern = vec![
+            Token::Ins(Begin),
+            Token::Ins(Push(Int(1))),
+            Token::AnySequence,
+            Token::Push(Capture("x".into())),
+            Token::Ins(End),
+        ];
+
+        let bindings = match_pattern(
+            &pattern,
+            &[
+                Begin,
+                Push(Int(1)),
+                If,
+                Push(Int(2)),
+                End,
+                Not,
+                Push(Int(7)),
+                End,
+            ],
+        );
+        assert_eq!(
+            Some(Bindings {
+                captures: vec![("x".into(), Int(7))],
+                holes: vec![(2, 6)],
+            }),
+            bindings
+        );
+        assert_eq!(
+            Some(vec![(2, 2)]),
+            match_pattern(&pattern, &[Begin, Push(Int(1)), Push(Int(3)), End]).map(|b| b.holes)
+        );
+        assert_eq!(None, match_pattern(&pattern, &[Begin, Push(Int(1)), End]));
+    }
+
+    #[test]
+    fn typed_operands() {
+        let by_type = vec![
+            Token::Ins(If),
+            Token::Push(Type(ValueType::Bool)),
+            Token::Push(Range(-2..2)),
+            Token::Ins(End),
+        ];
+
+        assert!(match_pattern(&by_type, &[If, Push(Bool(false)), Push(Int(-2)), End]).is_some());
+        assert_eq!(
+            None,
+            match_pattern(&by_type, &[If, Push(Int(0)), Push(Int(-2)), End])
+        );
+        assert_eq!(
+            None,
+            match_pattern(&by_type, &[If, Push(Bool(true)), Push(Bool(true)), End])
+        );
+        assert_eq!(
+            None,
+            match_pattern(&[Token::Ins(Push(Int(1)))], &[Push(Bool(true))])
+        );
+        assert_eq!(
+            Some(vec![("x".into(), Symbol("y".into()))]),
+            match_pattern(
+                &[Token::Push(Capture("x".into()))],
+                &[Push(Symbol("y".into()))]
+            )
+            .map(|b| b.captures)
+        );
+    }
+
+    #[test]
+    fn holes_in_arms_and_loops() {
+        let any_block = vec![Token::Ins(Loop), Token::AnyBlock, Token::Ins(End)];
+        let any_sequence = vec![Token::Ins(If), Token::AnySequence, Token::Ins(End)];
+
+        assert_eq!(
+            Some(vec![(1, 5)]),
+            match_pattern(&any_block, &[Loop, If, Else, Break, End, End]).map(|b| b.holes)
+        );
+        assert_eq!(
+            Some(vec![(1, 6)]),
+            match_pattern(&any_sequence, &[If, If, Push(Int(1)), Else, Pop, End, End])
+                .map(|b| b.holes)
+        );
+        assert_eq!(
+            None,
+            match_pattern(&any_sequence, &[If, Push(Int(1)), Else, Push(Int(2)), End])
+        );
+    }
+}
@@ -0,0 +1,1103 @@
+//!
+//! A registry of known execution blocks which is built once and then can be
+//! reused for matching any number of programs.
+//!
+
+use crate::bytecode;
+use crate::canonical;
+use crate::canonical::Canonical;
+use crate::fingerprint;
+use crate::fingerprint::BlockWalker;
+use crate::fingerprint::ClosedBlock;
+use crate::fingerprint::Fingerprint;
+use crate::fingerprint::Step;
+use crate::fuzzy;
+use crate::fuzzy::Similarity;
+use crate::occurrence::Automaton;
+use crate::occurrence::Occurrence;
+use crate::pattern;
+use crate::pattern::Bindings;
+use crate::pattern::Token;
+use crate::tree::BlockTree;
+use crate::tree::TreeBuilder;
+use crate::vm;
+use crate::vm::VmError;
+use crate::BlockInfo;
+use crate::BlockKind;
+use crate::Diagnostic;
+use crate::DiagnosticKind;
+use crate::Instruction;
+use crate::LenientMatches;
+use crate::MatchError;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// The registry of known execution blocks. Owns a prebuilt index of all
+/// registered blocks, so matching a program doesn't need to rebuild it.
+///
+/// Blocks are indexed by their Merkle-style fingerprints, so all blocks of a
+/// program are looked up in time linear to the program length, regardless of
+/// the nesting depth. Patterns with wildcard operands are indexed by shape
+/// fingerprints, which ignore operands, and are checked token by token only
+/// when the shape of a program block is the same.
+#[derive(Debug, Clone, Default)]
+pub struct BlockRegistry {
+    /// All known blocks in the registry order
+    entries: Vec<Entry>,
+    /// Indices of known blocks by their fingerprints
+    index: HashMap<Fingerprint, Vec<usize>>,
+    /// Indices of known patterns by their shape fingerprints
+    shapes: HashMap<Fingerprint, Vec<usize>>,
+    /// Indices of known patterns with holes, they can't be indexed
+    holed: Vec<usize>,
+    /// The greatest distance of fuzzy matches, fuzzy matching is off if None
+    max_distance: Option<usize>,
+    /// Indices and canonical forms of known blocks by fingerprints of their
+    /// canonical forms, canonical matching is off if None
+    canonical: Option<CanonicalIndex>,
+    /// The automaton of all plain blocks, it is built on the first search of
+    /// occurrences
+    automaton: OnceLock<Automaton>,
+}
+
+/// Indices and canonical forms of known blocks by canonical fingerprints.
+type CanonicalIndex = HashMap<Fingerprint, Vec<(usize, Vec<Canonical>)>>;
+
+/// A single entry of the registry.
+#[derive(Debug, Clone)]
+enum Entry {
+    /// A block which matches only exactly the same instructions.
+    Block(Vec<Instruction>),
+    /// A pattern with at least one token which isn't an exact instruction.
+    Pattern(Vec<Token>),
+}
+
+impl BlockRegistry {
+    /// Creates the registry from borrowed known execution blocks. The index of
+    /// each block in the registry is its position in `known_blocks`.
+    pub fn new(known_blocks: &[&[Instruction]]) -> Self {
+        known_blocks.iter().map(|block| block.to_vec()).collect()
+    }
+
+    /// Creates the registry from patterns of known execution blocks. The index
+    /// of each pattern in the registry is its position in `patterns`.
+    ///
+    /// A block which matches a pattern without wildcards (an exact block) is
+    /// always reported with the exact block. Otherwise the first matched
+    /// pattern in the registry order wins.
+    pub fn from_patterns<I: IntoIterator<Item = Vec<Token>>>(patterns: I) -> Self {
+        let mut registry = BlockRegistry::default();
+        for pattern in patterns {
+            registry.push_pattern(pattern);
+        }
+        registry
+    }
+
+    /// Turns on fuzzy matching. Each block without an exact match (or a match
+    /// with a pattern) is matched with the nearest registry entry, if the
+    /// Levenshtein distance between them isn't greater than `max_distance`.
+    /// Ties are resolved in favour of the first entry in the registry order.
+    ///
+    /// Tokens of patterns are compared with instructions one by one, so named
+    /// captures aren't checked for equality. Patterns with holes are never
+    /// matched fuzzily.
+    ///
+    /// Note that each unmatched block is compared with every registry entry,
+    /// so fuzzy matching is much slower than exact matching.
+    pub fn with_fuzzy_matching(mut self, max_distance: usize) -> Self {
+        self.max_distance = Some(max_distance);
+        self
+    }
+
+    /// Turns on canonical matching. Each block without an exact match (or a
+    /// match with a pattern) is matched with a registry block with the same
+    /// meaning, i.e. boolean instructions of both blocks are evaluated
+    /// symbolically and compared after removing double negations, applying De
+    /// Morgan's laws, folding constants and sorting operands of commutative
+    /// operations. The first equivalent block in the registry order wins.
+    ///
+    /// Only plain blocks are matched canonically, patterns are not.
+    pub fn with_canonical_matching(mut self) -> Self {
+        let mut index = CanonicalIndex::new();
+        for (idx, entry) in self.entries.iter().enumerate() {
+            if let Entry::Block(block) = entry {
+                if let Some(fingerprint) = fingerprint::canonical_fingerprint(block) {
+                    let form = canonical::canonical_form(block);
+                    index.entry(fingerprint).or_default().push((idx, form));
+                }
+            }
+        }
+        self.canonical = Some(index);
+        self
+    }
+
+    /// Validates all plain blocks of the registry, see [`validate_block`].
+    /// Returns registry indices of invalid blocks along with errors. Patterns
+    /// can't be executed, so they aren't validated.
+    ///
+    /// [`validate_block`]: crate::validate_block
+    pub fn validate(&self) -> Vec<(usize, VmError)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, entry)| match entry {
+                Entry::Block(block) => vm::validate_block(block).err().map(|err| (idx, err)),
+                Entry::Pattern(_) => None,
+            })
+            .collect()
+    }
+
+    /// Returns the number of registered blocks.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the registry contains no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Adds the block to the end of the registry. A block which isn't exactly
+    /// one well-formed block is kept, but never matched as a block, only its
+    /// occurrences are found. If the same block is already registered, the new
+    /// one takes precedence.
+    fn push(&mut self, block: Vec<Instruction>) {
+        let idx = self.entries.len();
+
+        if let Some(fingerprint) = fingerprint::fingerprint(&block) {
+            let candidates = self.index.entry(fingerprint).or_default();
+            let entries = &self.entries;
+            candidates.retain(|&known_idx| !entries[known_idx].is_block(&block));
+            candidates.push(idx);
+        }
+
+        self.entries.push(Entry::Block(block));
+    }
+
+    /// Adds the pattern to the end of the registry. A pattern without any
+    /// wildcard is registered as a plain block. A pattern with holes can't be
+    /// indexed, so it is checked against each block of a program.
+    fn push_pattern(&mut self, pattern: Vec<Token>) {
+        let exact: Option<Vec<Instruction>> = pattern.iter().map(Token::as_exact).collect();
+        if let Some(block) = exact {
+            return self.push(block);
+        }
+
+        let idx = self.entries.len();
+        let shape: Option<Vec<Instruction>> = pattern.iter().map(Token::shape).collect();
+        match shape {
+            Some(shape) => {
+                if let Some(fingerprint) = fingerprint::shape_fingerprint(&shape) {
+                    self.shapes.entry(fingerprint).or_default().push(idx);
+                }
+            }
+            None => self.holed.push(idx),
+        }
+
+        self.entries.push(Entry::Pattern(pattern));
+    }
+
+    /// Returns an index of the known block which is equal to the specified
+    /// program block.
+    fn lookup(&self, fingerprint: Fingerprint, block: &[Instruction]) -> Option<usize> {
+        self.index
+            .get(&fingerprint)?
+            .iter()
+            .copied()
+            .find(|&idx| self.entries[idx].is_block(block))
+    }
+
+    /// Returns an index of the first known pattern which matches the specified
+    /// program block along with its bindings.
+    fn lookup_pattern(
+        &self,
+        shape: Option<Fingerprint>,
+        block: &[Instruction],
+    ) -> Option<(usize, Bindings)> {
+        let shaped = shape
+            .and_then(|shape| self.shapes.get(&shape))
+            .into_iter()
+            .flatten()
+            .find_map(|&idx| self.match_entry(idx, block));
+        let limit = shaped.as_ref().map_or(self.entries.len(), |(idx, _)| *idx);
+
+        self.holed
+            .iter()
+            .take_while(|&&idx| idx < limit)
+            .find_map(|&idx| self.match_entry(idx, block))
+            .or(shaped)
+    }
+
+    /// Matches the pattern at the specified registry index with the block.
+    fn match_entry(&self, idx: usize, block: &[Instruction]) -> Option<(usize, Bindings)> {
+        match &self.entries[idx] {
+            Entry::Pattern(pattern) => {
+                pattern::match_pattern(pattern, block).map(|bindings| (idx, bindings))
+            }
+            Entry::Block(_) => None,
+        }
+    }
+
+    /// Returns an index of the first registry block which has the same
+    /// canonical form as the specified block, if canonical matching is on.
+    fn lookup_canonical(
+        &self,
+        canonical: Option<Fingerprint>,
+        block: &[Instruction],
+    ) -> Option<usize> {
+        let candidates = self.canonical.as_ref()?.get(&canonical?)?;
+        let form = canonical::canonical_form(block);
+        candidates
+            .iter()
+            .find(|(_, known_form)| *known_form == form)
+            .map(|(idx, _)| *idx)
+    }
+
+    /// Returns an index of the nearest registry entry to the block along with
+    /// their similarity, if fuzzy matching is on.
+    fn lookup_nearest(&self, block: &[Instruction]) -> Option<(usize, Similarity)> {
+        let max_distance = self.max_distance?;
+        let mut nearest: Option<(usize, Similarity)> = None;
+
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let limit = match nearest {
+                Some((_, similarity)) if similarity.distance == 0 => break,
+                Some((_, similarity)) => similarity.distance - 1,
+                None => max_distance,
+            };
+            let (distance, entry_len) = match entry {
+                Entry::Block(known) => (
+                    fuzzy::distance(known, block, limit, PartialEq::eq),
+                    known.len(),
+                ),
+                Entry::Pattern(pattern) if !pattern.iter().any(Token::is_hole) => (
+                    fuzzy::distance(pattern, block, limit, pattern::token_matches),
+                    pattern.len(),
+                ),
+                Entry::Pattern(_) => (None, 0),
+            };
+            if let Some(distance) = distance {
+                nearest = Some((idx, Similarity::new(distance, entry_len, block.len())));
+            }
+        }
+
+        nearest
+    }
+
+    /// Returns instructions of the plain block at the specified registry index,
+    /// None for a pattern or an index out of the registry.
+    pub(crate) fn block(&self, idx: usize) -> Option<&[Instruction]> {
+        match self.entries.get(idx)? {
+            Entry::Block(block) => Some(block),
+            Entry::Pattern(_) => None,
+        }
+    }
+
+    /// Looks up the closed block in the registry, `block` is instructions of
+    /// the closed block only. Fails if some instructions of the closed block
+    /// are missing.
+    pub(crate) fn match_block(
+        &self,
+        closed: &ClosedBlock,
+        block: &[Instruction],
+    ) -> Result<BlockInfo, MatchError> {
+        if block.len() != closed.end - closed.start + 1 {
+            return Err(MatchError::MissingInstructions {
+                position: closed.start,
+            });
+        }
+
+        let found = self
+            .lookup(closed.fingerprint, block)
+            .map(|idx| (idx, Bindings::default()))
+            .or_else(|| self.lookup_pattern(closed.shape, block))
+            .or_else(|| {
+                self.lookup_canonical(closed.canonical, block)
+                    .map(|idx| (idx, Bindings::default()))
+            });
+
+        let (registry_idx, bindings, similarity) = match found {
+            Some((idx, bindings)) => (Some(idx), bindings, Some(Similarity::EXACT)),
+            None => match self.lookup_nearest(block) {
+                Some((idx, similarity)) => (Some(idx), Bindings::default(), Some(similarity)),
+                None => (None, Bindings::default(), None),
+            },
+        };
+        let holes = bindings
+            .holes
+            .into_iter()
+            .map(|(start, end)| (closed.start + start, closed.start + end))
+            .collect();
+
+        let kind = match block[0] {
+            Instruction::If => BlockKind::If,
+            Instruction::Loop => BlockKind::Loop,
+            Instruction::Else => BlockKind::Else,
+            _ => BlockKind::Begin,
+        };
+
+        Ok(BlockInfo {
+            block_start_idx: closed.start,
+            block_end_idx: closed.end,
+            depth: closed.depth,
+            kind,
+            registry_idx,
+            captures: bindings.captures,
+            holes,
+            similarity,
+        })
+    }
+
+    /// Finds matches of execution blocks inside a program with this registry.
+    /// For each block in the program returns a vector with block start position
+    /// and block index in the registry. Note that order in the result vector is
+    /// corresponded to order of 'End' instructions for each block.
+    ///
+    /// 'Else' splits an 'If' block into two arms, which are matched as
+    /// separate blocks: 'If' up to 'Else' and 'Else' up to 'End'. The whole
+    /// 'If' block is matched right after its last arm, arms have the same
+    /// depth as the whole block.
+    ///
+    /// # Arguments
+    ///
+    /// * program - The program is a vector of blocks for matching with the registry.
+    ///
+    pub fn find_matches(&self, program: &[Instruction]) -> Result<Vec<BlockInfo>, MatchError> {
+        let mut result = Vec::new();
+        self.walk(program, 0..program.len(), |step| {
+            for closed in step.into_closed() {
+                result.push(self.match_block(&closed, closed.slice(program))?);
+            }
+            Ok(())
+        })?;
+        Ok(result)
+    }
+
+    /// Finds matches of execution blocks inside a program with this registry
+    /// and returns them as a tree, where children of a block are the blocks
+    /// nested into it, see [`BlockRegistry::find_matches`].
+    ///
+    /// # Arguments
+    ///
+    /// * program - The program is a vector of blocks for matching with the registry.
+    ///
+    pub fn find_block_tree(&self, program: &[Instruction]) -> Result<BlockTree, MatchError> {
+        let mut builder = TreeBuilder::default();
+        self.walk(program, 0..program.len(), |step| {
+            match step {
+                Step::Opened => builder.open(),
+                Step::Closed(closed) => {
+                    builder.close(self.match_block(&closed, closed.slice(program))?)
+                }
+                Step::Split(arm) => builder.split(self.match_block(&arm, arm.slice(program))?),
+                Step::Joined { arm, whole } => {
+                    builder.close(self.match_block(&arm, arm.slice(program))?);
+                    builder.close(self.match_block(&whole, whole.slice(program))?);
+                }
+                Step::UnmatchedEnd | Step::Inner => {}
+            }
+            Ok(())
+        })?;
+        Ok(builder.finish())
+    }
+
+    /// Walks through the span of the program and passes opened and closed
+    /// blocks to the visitor. The span is walked as if it was the whole
+    /// program, i.e. depths of blocks are counted from the start of the span,
+    /// but positions are in the whole program. Fails on the first unbalanced
+    /// block of the span or on the first failure of the visitor.
+    pub(crate) fn walk<F: FnMut(Step) -> Result<(), MatchError>>(
+        &self,
+        program: &[Instruction],
+        span: Range<usize>,
+        mut visit: F,
+    ) -> Result<(), MatchError> {
+        if program.is_empty() {
+            return Err(MatchError::NoOneBlockFound);
+        }
+
+        let mut walker = self.walker();
+
+        for ins_idx in span {
+            match walker.step(ins_idx, &program[ins_idx]) {
+                Step::UnmatchedEnd => return Err(MatchError::UnmatchedEnd { position: ins_idx }),
+                Step::Inner => {} // do nothing
+                step => visit(step)?,
+            }
+        }
+
+        let open_blocks = walker.open_blocks();
+        if open_blocks.is_empty() {
+            Ok(())
+        } else {
+            Err(MatchError::UnclosedBlocks {
+                starts: open_blocks,
+            })
+        }
+    }
+
+    /// Finds matches of execution blocks inside a program like
+    /// [`BlockRegistry::find_matches`], but doesn't fail on unbalanced blocks.
+    /// Each structural problem is recorded as a diagnostic and matching goes
+    /// on: a stray 'End' is ignored and blocks which are still open at the end
+    /// of the program are implicitly closed, the innermost one first.
+    ///
+    /// # Arguments
+    ///
+    /// * program - The program is a vector of blocks for matching with the registry.
+    ///
+    pub fn find_matches_lenient(
+        &self,
+        program: &[Instruction],
+    ) -> Result<LenientMatches, MatchError> {
+        if program.is_empty() {
+            return Err(MatchError::NoOneBlockFound);
+        }
+
+        let mut walker = self.walker();
+        let mut matches = LenientMatches::default();
+
+        for (ins_idx, instruction) in program.iter().enumerate() {
+            let step = walker.step(ins_idx, instruction);
+            if step == Step::UnmatchedEnd {
+                matches.diagnostics.push(Diagnostic {
+                    position: ins_idx,
+                    kind: DiagnosticKind::UnmatchedEnd,
+                });
+            }
+            for closed in step.into_closed() {
+                matches
+                    .blocks
+                    .push(self.match_block(&closed, closed.slice(program))?);
+            }
+        }
+
+        let open_blocks = walker.open_blocks();
+        if !open_blocks.is_empty() {
+            matches
+                .diagnostics
+                .extend(open_blocks.iter().map(|&start| Diagnostic {
+                    position: start,
+                    kind: DiagnosticKind::UnclosedBlock,
+                }));
+
+            // implicitly closed blocks are matched as if the program had the
+            // missing 'End' instructions at its end
+            let mut padded = program.to_vec();
+            for _ in &open_blocks {
+                let ins_idx = padded.len();
+                padded.push(Instruction::End);
+                for closed in walker.step(ins_idx, &Instruction::End).into_closed() {
+                    matches
+                        .blocks
+                        .push(self.match_block(&closed, closed.slice(&padded))?);
+                }
+            }
+        }
+
+        Ok(matches)
+    }
+
+    /// Creates the block walker which computes all fingerprints this registry
+    /// needs.
+    pub(crate) fn walker(&self) -> BlockWalker {
+        let mut walker = BlockWalker::default();
+        if !self.shapes.is_empty() {
+            walker = walker.with_shapes();
+        }
+        if self.canonical.is_some() {
+            walker = walker.with_canonical();
+        }
+        walker
+    }
+}
+
+impl BlockRegistry {
+    /// Finds matches of execution blocks inside a program encoded into the
+    /// bytecode, see [`BlockRegistry::find_matches`].
+    ///
+    /// # Arguments
+    ///
+    /// * bytecode - The program encoded by [`encode`](crate::encode).
+    ///
+    pub fn find_matches_in_bytecode(&self, bytecode: &[u8]) -> Result<Vec<BlockInfo>, MatchError> {
+        let program = bytecode::decode(bytecode).map_err(MatchError::InvalidBytecode)?;
+        self.find_matches(&program)
+    }
+}
+
+impl BlockRegistry {
+    /// Finds all occurrences of plain registry blocks in the program, not only
+    /// at block boundaries. Any instruction sequence may be registered as a
+    /// block to be found this way, e.g. a snippet without 'Begin' and 'End'.
+    /// Overlapping occurrences are all reported, ordered by their ends and
+    /// then by their starts. Patterns with wildcards are never found.
+    ///
+    /// The automaton of registry blocks is built on the first call, so the
+    /// program is scanned once regardless of the registry size.
+    ///
+    /// # Arguments
+    ///
+    /// * program - The program to search in, blocks don't have to be balanced.
+    ///
+    pub fn find_occurrences(&self, program: &[Instruction]) -> Vec<Occurrence> {
+        self.automaton
+            .get_or_init(|| {
+                Automaton::new(self.entries.iter().enumerate().filter_map(
+                    |(idx, entry)| match entry {
+                        Entry::Block(block) => Some((idx, block.as_slice())),
+                        Entry::Pattern(_) => None,
+                    },
+                ))
+            })
+            .find(program)
+    }
+}
+
+impl Entry {
+    /// Returns true if this entry is exactly the specified block.
+    fn is_block(&self, block: &[Instruction]) -> bool {
+        match self {
+            Entry::Block(known) => known.as_slice() == block,
+            Entry::Pattern(_) => false,
+        }
+    }
+}
+
+impl From<Vec<Vec<Instruction>>> for BlockRegistry {
+    /// Creates the registry from owned known execution blocks.
+    fn from(known_blocks: Vec<Vec<Instruction>>) -> Self {
+        known_blocks.into_iter().collect()
+    }
+}
+
+impl std::iter::FromIterator<Vec<Instruction>> for BlockRegistry {
+    fn from_iter<I: IntoIterator<Item = Vec<Instruction>>>(known_blocks: I) -> Self {
+        let mut registry = BlockRegistry::default();
+        for block in known_blocks {
+            registry.push(block);
+        }
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encode;
+    use crate::fingerprint::Step;
+    use crate::tests::matched;
+    use crate::tests::not_matched;
+    use crate::BlockInfo;
+    use crate::BlockKind;
+    use crate::BlockRegistry;
+    use crate::Diagnostic;
+    use crate::DiagnosticKind;
+    use crate::Instruction;
+    use crate::Instruction::*;
+    use crate::MatchError;
+    use crate::Operand::*;
+    use crate::Similarity;
+    use crate::Token;
+    use crate::Token::Ins;
+    use crate::Value::*;
+    use crate::VmErrorKind;
+
+    #[test]
+    fn registry_is_reusable() {
+        let registry = BlockRegistry::from(vec![
+            vec![Begin, Push(Int(1)), End],
+            vec![If, Push(Int(2)), Push(Int(3)), End],
+        ]);
+
+        let first_program = vec![Begin, Push(Int(1)), End];
+        let second_program = vec![Begin, If, Push(Int(2)), Push(Int(3)), End, End];
+
+        let first = registry.find_matches(&first_program).unwrap();
+        let second = registry.find_matches(&second_program).unwrap();
+
+        assert_eq!(vec![matched(0, 2, 0, BlockKind::Begin, 0)], first);
+        assert_eq!(
+            vec![
+                matched(1, 4, 1, BlockKind::If, 1),
+                not_matched(0, 5, 0, BlockKind::Begin)
+            ],
+            second
+        );
+    }
+
+    #[test]
+    fn borrowed_and_owned_registries_are_equal() {
+        let borrowed = BlockRegistry::new(&[&[If, End], &[Begin, End]]);
+        let owned = BlockRegistry::from(vec![vec![If, End], vec![Begin, End]]);
+        let program = vec![Begin, If, End, End];
+
+        assert_eq!(2, borrowed.len());
+        assert_eq!(
+            borrowed.find_matches(&program).unwrap(),
+            owned.find_matches(&program).unwrap()
+        );
+    }
+
+    #[test]
+    fn duplicated_block_matches_the_last_one() {
+        let registry = BlockRegistry::new(&[&[If, End], &[Begin, End], &[If, End]]);
+
+        let program = vec![If, End];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(vec![matched(0, 1, 0, BlockKind::If, 2)], result);
+    }
+
+    #[test]
+    fn not_a_block_is_never_matched() {
+        let registry = BlockRegistry::new(&[&[If, End, If, End], &[Push(Int(1))]]);
+
+        let program = vec![If, End, If, End];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(2, registry.len());
+        assert_eq!(
+            vec![
+                not_matched(0, 1, 0, BlockKind::If),
+                not_matched(2, 3, 0, BlockKind::If)
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(If), Token::Push(Any), Ins(Push(Int(3))), Ins(End)],
+            vec![
+                Ins(If),
+                Token::Push(Range(0..10)),
+                Ins(Push(Int(3))),
+                Ins(End),
+            ],
+            vec![Ins(If), Ins(Push(Int(2))), Ins(Push(Int(3))), Ins(End)],
+            vec![
+                Ins(Begin),
+                Token::Push(Capture("x".into())),
+                Token::Push(Capture("x".into())),
+                Ins(End),
+            ],
+        ]);
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(7)),
+            Push(Int(3)),
+            End,
+            If,
+            Push(Int(2)),
+            Push(Int(3)),
+            End,
+            Begin,
+            Push(Int(5)),
+            Push(Int(5)),
+            End,
+            Begin,
+            Push(Int(5)),
+            Push(Int(6)),
+            End,
+            End,
+        ];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(
+            vec![
+                matched(1, 4, 1, BlockKind::If, 0),
+                matched(5, 8, 1, BlockKind::If, 2),
+                BlockInfo {
+                    captures: vec![("x".into(), Int(5))],
+                    ..matched(9, 12, 1, BlockKind::Begin, 3)
+                },
+                not_matched(13, 16, 1, BlockKind::Begin),
+                not_matched(0, 17, 0, BlockKind::Begin),
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn patterns_with_holes() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![
+                Ins(If),
+                Token::Push(Capture("x".into())),
+                Token::AnyBlock,
+                Ins(End),
+            ],
+            vec![Ins(Begin), Ins(Push(Int(1))), Token::AnySequence, Ins(End)],
+            vec![Ins(If), Ins(End)],
+        ]);
+        let program = vec![
+            Begin,
+            Push(Int(1)),
+            If,
+            Push(Int(4)),
+            If,
+            End,
+            End,
+            Not,
+            End,
+        ];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(
+            vec![
+                matched(4, 5, 2, BlockKind::If, 2),
+                BlockInfo {
+                    captures: vec![("x".into(), Int(4))],
+                    holes: vec![(4, 6)],
+                    ..matched(2, 6, 1, BlockKind::If, 0)
+                },
+                BlockInfo {
+                    holes: vec![(2, 8)],
+                    ..matched(0, 8, 0, BlockKind::Begin, 1)
+                },
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn first_matched_pattern_wins() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(If), Token::AnySequence, Ins(End)],
+            vec![Ins(If), Token::Push(Any), Ins(End)],
+        ]);
+
+        let result = registry.find_matches(&[If, Push(Int(1)), End]).unwrap();
+
+        assert_eq!(Some(0), result[0].registry_idx);
+    }
+
+    #[test]
+    fn fuzzy_matching() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(If), Ins(Push(Int(2))), Ins(Push(Int(3))), Ins(End)],
+            vec![Ins(If), Token::Push(Any), Ins(Or), Ins(End)],
+            vec![
+                Ins(Begin),
+                Ins(Push(Int(1))),
+                Ins(Push(Int(2))),
+                Ins(Push(Int(3))),
+                Ins(End),
+            ],
+        ])
+        .with_fuzzy_matching(1);
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(2)),
+            Not,
+            Push(Int(3)),
+            End,
+            If,
+            Push(Int(9)),
+            Not,
+            Or,
+            End,
+            End,
+        ];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        let similarities: Vec<_> = result
+            .iter()
+            .map(|info| (info.registry_idx, info.similarity))
+            .collect();
+        assert_eq!(
+            vec![
+                (Some(0), Some(Similarity::new(1, 4, 5))),
+                (Some(1), Some(Similarity::new(1, 4, 5))),
+                (None, None),
+            ],
+            similarities
+        );
+    }
+
+    #[test]
+    fn fuzzy_matching_prefers_nearest_entry() {
+        let registry = BlockRegistry::new(&[
+            &[If, Push(Int(1)), Push(Int(1)), End],
+            &[If, Push(Int(2)), Push(Int(1)), End],
+            &[If, Push(Int(2)), Push(Int(3)), End],
+        ])
+        .with_fuzzy_matching(2);
+
+        let result = registry
+            .find_matches(&[If, Push(Int(2)), Push(Int(3)), Not, End])
+            .unwrap();
+
+        assert_eq!(Some(2), result[0].registry_idx);
+        assert_eq!(1, result[0].similarity.unwrap().distance);
+    }
+
+    #[test]
+    fn canonical_matching() {
+        let known_blocks: Vec<&[Instruction]> = vec![
+            &[If, Push(Int(2)), Push(Int(3)), Or, End],
+            &[Begin, Push(Int(2)), Push(Int(3)), And, Not, End],
+        ];
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(3)),
+            Push(Int(2)),
+            Or,
+            End,
+            Push(Int(2)),
+            Not,
+            Push(Int(3)),
+            Not,
+            Or,
+            End,
+        ];
+
+        let exact = BlockRegistry::new(&known_blocks).find_matches(&program);
+        let canonical = BlockRegistry::new(&known_blocks)
+            .with_canonical_matching()
+            .find_matches(&program);
+
+        assert_eq!(
+            vec![
+                not_matched(1, 5, 1, BlockKind::If),
+                not_matched(0, 11, 0, BlockKind::Begin)
+            ],
+            exact.unwrap()
+        );
+        assert_eq!(
+            vec![
+                matched(1, 5, 1, BlockKind::If, 0),
+                not_matched(0, 11, 0, BlockKind::Begin)
+            ],
+            canonical.unwrap()
+        );
+    }
+
+    #[test]
+    fn canonical_matching_of_whole_block() {
+        let registry = BlockRegistry::new(&[&[Begin, Push(Int(2)), Push(Int(3)), And, Not, End]])
+            .with_canonical_matching();
+
+        let program = vec![
+            Begin,
+            Push(Int(3)),
+            Not,
+            Push(Int(2)),
+            Not,
+            Or,
+            Push(Bool(true)),
+            And,
+            End,
+        ];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(vec![matched(0, 8, 0, BlockKind::Begin, 0)], result);
+    }
+
+    #[test]
+    fn arms_and_whole_block_are_matched() {
+        let registry = BlockRegistry::new(&[
+            &[If, Push(Int(2)), Else],
+            &[Else, Push(Int(3)), End],
+            &[Loop, Break, End],
+            &[If, Push(Int(2)), Else, Push(Int(3)), End],
+        ]);
+
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(2)),
+            Else,
+            Push(Int(3)),
+            End,
+            Loop,
+            Break,
+            End,
+            End,
+        ];
+
+        let result = registry.find_matches(&program).unwrap();
+
+        assert_eq!(
+            vec![
+                matched(1, 3, 1, BlockKind::If, 0),
+                matched(3, 5, 1, BlockKind::Else, 1),
+                matched(1, 5, 1, BlockKind::If, 3),
+                matched(6, 8, 1, BlockKind::Loop, 2),
+                not_matched(0, 9, 0, BlockKind::Begin)
+            ],
+            result
+        );
+        assert!(registry.validate().is_empty());
+    }
+
+    #[test]
+    fn block_with_missing_instructions() {
+        let registry = BlockRegistry::new(&[&[If, End]]);
+        let mut walker = registry.walker();
+        walker.step(3, &If);
+        let closed = match walker.step(4, &End) {
+            Step::Closed(closed) => closed,
+            _ => panic!("the block isn't closed"),
+        };
+
+        assert_eq!(
+            Err(MatchError::MissingInstructions { position: 3 }),
+            registry.match_block(&closed, &[])
+        );
+        assert_eq!(
+            Ok(Some(0)),
+            registry
+                .match_block(&closed, &[If, End])
+                .map(|info| info.registry_idx())
+        );
+    }
+
+    #[test]
+    fn else_closes_only_if_block() {
+        let registry = BlockRegistry::default();
+
+        assert_eq!(
+            Err(MatchError::UnmatchedEnd { position: 1 }),
+            registry.find_matches(&[Begin, Else, End])
+        );
+        assert_eq!(
+            Err(MatchError::UnmatchedEnd { position: 2 }),
+            registry.find_matches(&[If, Else, Else, End])
+        );
+    }
+
+    #[test]
+    fn registry_validation() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(Begin), Ins(Push(Int(1))), Ins(End)],
+            vec![Ins(If), Ins(Push(Int(2))), Ins(Not), Ins(End)],
+            vec![Ins(If), Token::Push(Any), Ins(Not), Ins(End)],
+            vec![Ins(Begin), Ins(And), Ins(End)],
+        ]);
+
+        let errors: Vec<_> = registry
+            .validate()
+            .into_iter()
+            .map(|(idx, err)| (idx, err.kind))
+            .collect();
+
+        assert_eq!(
+            vec![
+                (1, VmErrorKind::TypeError { value: Int(2) }),
+                (3, VmErrorKind::StackUnderflow)
+            ],
+            errors
+        );
+    }
+
+    #[test]
+    fn occurrences_of_snippets() {
+        let registry = BlockRegistry::from_patterns(vec![
+            vec![Ins(Push(Int(2))), Ins(Not), Ins(Push(Int(3)))],
+            vec![Ins(If), Token::Push(Any), Ins(End)],
+            vec![Ins(If), Ins(Push(Int(3))), Ins(End)],
+        ]);
+        let program = vec![
+            Begin,
+            Push(Int(2)),
+            Not,
+            Push(Int(3)),
+            If,
+            Push(Int(3)),
+            End,
+            End,
+        ];
+
+        let occurrences: Vec<_> = registry
+            .find_occurrences(&program)
+            .into_iter()
+            .map(|found| (found.registry_idx, found.start, found.end))
+            .collect();
+
+        assert_eq!(vec![(0, 1, 3), (2, 4, 6)], occurrences);
+        assert!(registry.find_occurrences(&[]).is_empty());
+    }
+
+    #[test]
+    fn matching_bytecode() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(300)), End]]);
+        let program = vec![Begin, If, Push(Int(300)), End, End];
+        let bytecode = encode(&program);
+
+        let result = registry.find_matches_in_bytecode(&bytecode).unwrap();
+        let truncated = registry.find_matches_in_bytecode(&bytecode[..8]);
+
+        assert_eq!(
+            vec![
+                matched(1, 3, 1, BlockKind::If, 0),
+                not_matched(0, 4, 0, BlockKind::Begin)
+            ],
+            result
+        );
+        assert_eq!(
+            "Invalid bytecode: Decode error at the offset 8: unexpected end of bytecode",
+            truncated.unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn lenient_matching_recovers_from_unbalanced_blocks() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(2)), End], &[Begin, Push(Int(1)), End]]);
+        let program = vec![End, If, Push(Int(2)), End, End, Begin, Begin, Push(Int(1))];
+
+        let result = registry.find_matches_lenient(&program).unwrap();
+
+        assert_eq!(
+            vec![
+                matched(1, 3, 0, BlockKind::If, 0),
+                matched(6, 8, 1, BlockKind::Begin, 1),
+                not_matched(5, 9, 0, BlockKind::Begin)
+            ],
+            result.blocks
+        );
+        assert_eq!(
+            vec![
+                Diagnostic {
+                    position: 0,
+                    kind: DiagnosticKind::UnmatchedEnd,
+                },
+                Diagnostic {
+                    position: 4,
+                    kind: DiagnosticKind::UnmatchedEnd,
+                },
+                Diagnostic {
+                    position: 5,
+                    kind: DiagnosticKind::UnclosedBlock,
+                },
+                Diagnostic {
+                    position: 6,
+                    kind: DiagnosticKind::UnclosedBlock,
+                },
+            ],
+            result.diagnostics
+        );
+    }
+
+    #[test]
+    fn lenient_matching_of_balanced_program() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(2)), End]]);
+        let program = vec![Begin, If, Push(Int(2)), End, End];
+
+        let result = registry.find_matches_lenient(&program).unwrap();
+
+        assert_eq!(registry.find_matches(&program).unwrap(), result.blocks);
+        assert!(result.diagnostics.is_empty());
+    }
+}
@@ -0,0 +1,276 @@
+//!
+//! A text file format of registries. The file starts with the schema version
+//! and consists of named entries, each entry may have a description and is
+//! followed by its pattern in the textual assembly form, see [`crate::parse_pattern`].
+//!
+//! ```text
+//! registry 1                ; the schema version
+//!
+//! entry if-two-constants    ; a unique entry name
+//! description Pushes two constants when the condition is true
+//! if
+//!   push _
+//!   push 3
+//! end
+//!
+//! entry any-begin
+//! begin ... end
+//! ```
+//!
+//! A description lasts up to the end of the line and may be repeated to
+//! describe an entry with a few lines, comments aren't stripped from it. An
+//! operand has to be on the same line as its instruction.
+//!
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::asm;
+use crate::asm::ParseError;
+use crate::asm::ParseErrorKind;
+use crate::asm::PatternListing;
+use crate::BlockRegistry;
+use crate::Token;
+
+/// The current schema version of registry files.
+pub const SCHEMA_VERSION: u32 = 1;
+
+const REGISTRY: &str = "registry";
+const ENTRY: &str = "entry";
+const DESCRIPTION: &str = "description";
+
+/// A registry file, i.e. named registry entries in the registry order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RegistryFile {
+    pub entries: Vec<RegistryEntry>,
+}
+
+/// A single named entry of a registry file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryEntry {
+    /// A unique name of the entry
+    pub name: String,
+    /// An optional human-readable description, may contain line breaks
+    pub description: Option<String>,
+    pub pattern: Vec<Token>,
+}
+
+impl RegistryFile {
+    /// Builds the registry from patterns of all entries. The index of each
+    /// entry in the registry is its position in the file.
+    pub fn to_registry(&self) -> BlockRegistry {
+        BlockRegistry::from_patterns(self.entries.iter().map(|entry| entry.pattern.clone()))
+    }
+}
+
+/// Parses the registry file from its text.
+pub fn parse_registry(text: &str) -> Result<RegistryFile, ParseError> {
+    let mut file = RegistryFile::default();
+    let mut names = HashSet::new();
+    let mut has_version = false;
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let words = asm::line_words(line, line_idx + 1);
+        let first = match words.first() {
+            Some(first) => *first,
+            None => continue,
+        };
+        let keyword = first.text.to_lowercase();
+
+        if !has_version {
+            if keyword != REGISTRY {
+                return Err(first.error(ParseErrorKind::MissingVersion));
+            }
+            let version = words
+                .get(1)
+                .ok_or_else(|| first.error(ParseErrorKind::MissingVersion))?;
+            if version.text.parse() != Ok(SCHEMA_VERSION) {
+                let kind = ParseErrorKind::UnsupportedVersion(version.text.to_string());
+                return Err(version.error(kind));
+            }
+            if let Some(extra) = words.get(2) {
+                return Err(extra.error(ParseErrorKind::InvalidOperand(extra.text.to_string())));
+            }
+            has_version = true;
+            continue;
+        }
+
+        match keyword.as_str() {
+            ENTRY => {
+                let name = words
+                    .get(1)
+                    .ok_or_else(|| first.error(ParseErrorKind::MissingEntryName))?;
+                if !names.insert(name.text) {
+                    return Err(name.error(ParseErrorKind::DuplicateEntry(name.text.to_string())));
+                }
+                if let Some(extra) = words.get(2) {
+                    let kind = ParseErrorKind::InvalidOperand(extra.text.to_string());
+                    return Err(extra.error(kind));
+                }
+                file.entries.push(RegistryEntry {
+                    name: name.text.to_string(),
+                    description: None,
+                    pattern: Vec::new(),
+                });
+            }
+            DESCRIPTION => {
+                let entry = file
+                    .entries
+                    .last_mut()
+                    .ok_or_else(|| first.error(ParseErrorKind::OutsideEntry))?;
+                let text = line.trim_start()[first.text.len()..].trim();
+                match entry.description.as_mut() {
+                    Some(description) => {
+                        description.push('\n');
+                        description.push_str(text);
+                    }
+                    None => entry.description = Some(text.to_string()),
+                }
+            }
+            _ => {
+                let entry = file
+                    .entries
+                    .last_mut()
+                    .ok_or_else(|| first.error(ParseErrorKind::OutsideEntry))?;
+                let mut words = words.into_iter();
+                while let Some(word) = words.next() {
+                    entry.pattern.push(asm::parse_token(word, &mut words)?);
+                }
+            }
+        }
+    }
+
+    if has_version {
+        Ok(file)
+    } else {
+        Err(ParseError {
+            line: 1,
+            column: 1,
+            kind: ParseErrorKind::MissingVersion,
+        })
+    }
+}
+
+impl Display for RegistryFile {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "{} {}", REGISTRY, SCHEMA_VERSION)?;
+
+        for entry in &self.entries {
+            writeln!(f)?;
+            writeln!(f, "{} {}", ENTRY, entry.name)?;
+            if let Some(description) = &entry.description {
+                for line in description.lines() {
+                    writeln!(f, "{} {}", DESCRIPTION, line)?;
+                }
+            }
+            write!(f, "{}", PatternListing(&entry.pattern))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::ParseErrorKind;
+    use crate::registry_file::parse_registry;
+    use crate::registry_file::RegistryEntry;
+    use crate::registry_file::RegistryFile;
+    use crate::Instruction::*;
+    use crate::Operand;
+    use crate::Token;
+    use crate::Value::*;
+
+    #[test]
+    fn parse_registry_file() {
+        let text = "
+            ; known blocks
+            registry 1
+
+            entry if-two-constants
+            description Pushes two constants; the first one is any
+            description when the condition is true
+            if push _ push 3 end
+
+            entry begin-one
+            begin
+              push 1
+            end
+        ";
+
+        let file = parse_registry(text).unwrap();
+
+        assert_eq!(
+            RegistryFile {
+                entries: vec![
+                    RegistryEntry {
+                        name: "if-two-constants".into(),
+                        description: Some(
+                            "Pushes two constants; the first one is any\nwhen the condition is true"
+                                .into()
+                        ),
+                        pattern: vec![
+                            Token::Ins(If),
+                            Token::Push(Operand::Any),
+                            Token::Ins(Push(Int(3))),
+                            Token::Ins(End),
+                        ],
+                    },
+                    RegistryEntry {
+                        name: "begin-one".into(),
+                        description: None,
+                        pattern: vec![Token::Ins(Begin), Token::Ins(Push(Int(1))), Token::Ins(End)],
+                    },
+                ]
+            },
+            file
+        );
+    }
+
+    #[test]
+    fn print_and_parse_round_trip() {
+        let text = "registry 1\n\nentry first\ndescription The first entry\nif\n  push ?x\n  <block>\nend\n\nentry second\nbegin\n  ...\n  push $a\\u{3b}b\nend\n";
+
+        let file = parse_registry(text).unwrap();
+
+        assert_eq!(text, file.to_string());
+    }
+
+    #[test]
+    fn invalid_registry_files() {
+        let kind = |text: &str| parse_registry(text).map_err(|err| err.kind);
+
+        assert_eq!(Err(ParseErrorKind::MissingVersion), kind(""));
+        assert_eq!(Err(ParseErrorKind::MissingVersion), kind("entry a\nif end"));
+        assert_eq!(
+            Err(ParseErrorKind::UnsupportedVersion("2".into())),
+            kind("registry 2")
+        );
+        assert_eq!(
+            Err(ParseErrorKind::OutsideEntry),
+            kind("registry 1\nif end")
+        );
+        assert_eq!(
+            Err(ParseErrorKind::MissingEntryName),
+            kind("registry 1\nentry")
+        );
+        assert_eq!(
+            Err(ParseErrorKind::DuplicateEntry("a".into())),
+            kind("registry 1\nentry a\nif end\nentry a")
+        );
+    }
+
+    #[test]
+    fn registry_from_file() {
+        let file =
+            parse_registry("registry 1\nentry a\nif push 1 end\nentry b\nif push _ end").unwrap();
+        let registry = file.to_registry();
+
+        let result = registry.find_matches(&[If, Push(Int(2)), End]).unwrap();
+
+        assert_eq!(2, registry.len());
+        assert_eq!(Some(1), result[0].registry_idx);
+    }
+}
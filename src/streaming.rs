@@ -0,0 +1,291 @@
+//!
+//! Matching of programs which arrive instruction by instruction, so the whole
+//! program is never kept in memory.
+//!
+
+use std::vec;
+
+use crate::fingerprint::BlockWalker;
+use crate::fingerprint::Step;
+use crate::BlockInfo;
+use crate::BlockRegistry;
+use crate::Instruction;
+use crate::MatchError;
+
+/// Matches blocks of a program with the registry while the program is being
+/// received. Each block is matched as soon as its 'End' arrives, only
+/// instructions of currently opened blocks are buffered.
+///
+/// Blocks are reported in the same order as [`BlockRegistry::find_matches`]
+/// reports them, i.e. in the order of 'End' instructions.
+pub struct StreamingMatcher<'r> {
+    registry: &'r BlockRegistry,
+    walker: BlockWalker,
+    /// Instructions of currently opened blocks, empty if there is no opened
+    /// block
+    buffer: Vec<Instruction>,
+    /// An index of the first buffered instruction in the whole program
+    offset: usize,
+    /// An index of the next instruction in the whole program
+    position: usize,
+}
+
+impl<'r> StreamingMatcher<'r> {
+    /// Creates the matcher of a new program with the registry.
+    pub fn new(registry: &'r BlockRegistry) -> Self {
+        StreamingMatcher {
+            registry,
+            walker: registry.walker(),
+            buffer: Vec::new(),
+            offset: 0,
+            position: 0,
+        }
+    }
+
+    /// Feeds the next instruction of the program to the matcher. Returns the
+    /// matched blocks which the instruction closes, i.e. an arm of a split
+    /// block or the last arm along with the whole split block.
+    ///
+    /// A stray 'End' or 'Else' is reported as [`MatchError::UnmatchedEnd`] and
+    /// skipped, so the caller may go on with the next instructions.
+    pub fn push(&mut self, instruction: Instruction) -> Result<Vec<BlockInfo>, MatchError> {
+        let position = self.position;
+        self.position += 1;
+
+        match self.walker.step(position, &instruction) {
+            Step::Opened => {
+                if self.buffer.is_empty() {
+                    self.offset = position;
+                }
+                self.buffer.push(instruction);
+                Ok(Vec::new())
+            }
+            Step::Inner => {
+                if !self.buffer.is_empty() {
+                    self.buffer.push(instruction);
+                }
+                Ok(Vec::new())
+            }
+            Step::UnmatchedEnd => {
+                // a stray instruction inside a block is still a part of it
+                if !self.buffer.is_empty() {
+                    self.buffer.push(instruction);
+                }
+                Err(MatchError::UnmatchedEnd { position })
+            }
+            step => {
+                // a split block is still opened after its first arm, so the
+                // buffer is kept until the whole block is closed
+                self.buffer.push(instruction);
+                let mut infos = Vec::new();
+                let mut is_top_level = false;
+                for closed in step.into_closed() {
+                    let block = &self.buffer[closed.start - self.offset..];
+                    infos.push(self.registry.match_block(&closed, block)?);
+                    is_top_level = closed.depth == 0 && !closed.is_arm;
+                }
+                if is_top_level {
+                    self.buffer.clear();
+                }
+                Ok(infos)
+            }
+        }
+    }
+
+    /// Finishes the program, fails if it was empty or some blocks weren't
+    /// closed.
+    pub fn finish(self) -> Result<(), MatchError> {
+        if self.position == 0 {
+            return Err(MatchError::NoOneBlockFound);
+        }
+        let open_blocks = self.walker.open_blocks();
+        if open_blocks.is_empty() {
+            Ok(())
+        } else {
+            Err(MatchError::UnclosedBlocks {
+                starts: open_blocks,
+            })
+        }
+    }
+
+    /// Turns the matcher into an iterator of matched blocks of the program,
+    /// which is read from the specified instructions lazily. The error of
+    /// [`StreamingMatcher::finish`] is the last item, if any.
+    pub fn matches<I>(self, instructions: I) -> StreamMatches<'r, I::IntoIter>
+    where
+        I: IntoIterator<Item = Instruction>,
+    {
+        StreamMatches {
+            matcher: Some(self),
+            instructions: instructions.into_iter(),
+            pending: Vec::new().into_iter(),
+        }
+    }
+}
+
+/// An iterator of matched blocks of a streamed program, see
+/// [`StreamingMatcher::matches`].
+pub struct StreamMatches<'r, I> {
+    /// The matcher, None if the program is finished
+    matcher: Option<StreamingMatcher<'r>>,
+    instructions: I,
+    /// Matched blocks which were closed by the same instruction, but weren't
+    /// returned yet
+    pending: vec::IntoIter<BlockInfo>,
+}
+
+impl<I: Iterator<Item = Instruction>> Iterator for StreamMatches<'_, I> {
+    type Item = Result<BlockInfo, MatchError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(info) = self.pending.next() {
+            return Some(Ok(info));
+        }
+        let matcher = self.matcher.as_mut()?;
+
+        for instruction in &mut self.instructions {
+            match matcher.push(instruction) {
+                Ok(infos) => {
+                    self.pending = infos.into_iter();
+                    if let Some(info) = self.pending.next() {
+                        return Some(Ok(info));
+                    }
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+
+        self.matcher.take()?.finish().err().map(Err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BlockRegistry;
+    use crate::DiagnosticKind;
+    use crate::Instruction;
+    use crate::Instruction::*;
+    use crate::MatchError;
+    use crate::StreamingMatcher;
+    use crate::Value::*;
+
+    #[test]
+    fn streamed_matches_are_same_as_whole_program_matches() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(2)), End], &[Begin, Push(Int(1)), End]]);
+        let program = vec![
+            Push(Int(7)),
+            Begin,
+            If,
+            Push(Int(2)),
+            End,
+            Push(Int(1)),
+            End,
+            Begin,
+            Push(Int(1)),
+            End,
+        ];
+
+        let streamed: Result<Vec<_>, _> = StreamingMatcher::new(&registry)
+            .matches(program.clone())
+            .collect();
+
+        assert_eq!(registry.find_matches(&program), streamed);
+    }
+
+    #[test]
+    fn streamed_arms_are_same_as_whole_program_arms() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(2)), Else], &[Else, Push(Int(3)), End]]);
+        let program = vec![
+            If,
+            Push(Int(2)),
+            Else,
+            Loop,
+            If,
+            Else,
+            Break,
+            End,
+            End,
+            Push(Int(3)),
+            End,
+        ];
+
+        let streamed: Result<Vec<_>, _> = StreamingMatcher::new(&registry)
+            .matches(program.clone())
+            .collect();
+
+        assert_eq!(registry.find_matches(&program), streamed);
+    }
+
+    #[test]
+    fn blocks_are_emitted_on_their_end() {
+        let registry = BlockRegistry::new(&[&[If, End]]);
+        let mut matcher = StreamingMatcher::new(&registry);
+
+        assert_eq!(Ok(vec![]), matcher.push(Begin));
+        assert_eq!(Ok(vec![]), matcher.push(If));
+        let inner = matcher.push(End).unwrap();
+        assert_eq!(1, inner.len());
+        assert_eq!(Some(0), inner[0].registry_idx());
+        assert_eq!(
+            (1, 2),
+            (inner[0].block_start_idx(), inner[0].block_end_idx())
+        );
+        assert_eq!(
+            Err(MatchError::UnclosedBlocks { starts: vec![0] }),
+            matcher.finish()
+        );
+    }
+
+    #[test]
+    fn stream_errors() {
+        let registry = BlockRegistry::default();
+
+        let stray: Vec<_> = StreamingMatcher::new(&registry)
+            .matches(vec![End, Begin, End])
+            .collect();
+        let empty: Vec<_> = StreamingMatcher::new(&registry).matches(vec![]).collect();
+
+        assert_eq!(Err(MatchError::UnmatchedEnd { position: 0 }), stray[0]);
+        assert_eq!(2, stray.len());
+        assert_eq!(vec![Err(MatchError::NoOneBlockFound)], empty);
+    }
+
+    #[test]
+    fn stream_goes_on_after_stray_instructions() {
+        let registry = BlockRegistry::new(&[&[If, End], &[Begin, Else, If, End, End]]);
+
+        let programs: [&[Instruction]; 3] = [
+            &[Begin, Else, If, End, End],
+            &[Begin, Else, Else, If, End, End],
+            &[End, Loop, If, Else, End, Else, End, End, Begin, Else],
+        ];
+
+        for program in programs.iter().copied() {
+            let mut matcher = StreamingMatcher::new(&registry);
+            let mut blocks = Vec::new();
+            let mut strays = Vec::new();
+            for instruction in program.iter().cloned() {
+                match matcher.push(instruction) {
+                    Ok(infos) => blocks.extend(infos),
+                    Err(MatchError::UnmatchedEnd { position }) => strays.push(position),
+                    Err(err) => panic!("unexpected error: {}", err),
+                }
+            }
+
+            let lenient = registry.find_matches_lenient(program).unwrap();
+            let unmatched_ends: Vec<_> = lenient
+                .diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.kind == DiagnosticKind::UnmatchedEnd)
+                .map(|diagnostic| diagnostic.position)
+                .collect();
+            let closed_blocks: Vec<_> = lenient
+                .blocks
+                .into_iter()
+                .filter(|info| info.block_end_idx() < program.len())
+                .collect();
+            assert_eq!(closed_blocks, blocks);
+            assert_eq!(unmatched_ends, strays);
+        }
+    }
+}
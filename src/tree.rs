@@ -0,0 +1,293 @@
+//!
+//! A tree of matched blocks, which keeps the nesting structure of a program.
+//!
+//! Nodes are stored in a single vector in the order of 'End' instructions,
+//! i.e. in post-order, and refer to each other by indices in this vector. So
+//! the tree is never dropped or traversed recursively, even for a very deep
+//! program.
+//!
+
+use std::slice;
+
+use crate::BlockInfo;
+
+/// A tree of matched blocks, see [`BlockRegistry::find_block_tree`].
+///
+/// [`BlockRegistry::find_block_tree`]: crate::BlockRegistry::find_block_tree
+#[derive(Debug, Default, PartialEq)]
+pub struct BlockTree {
+    /// All nodes in post-order
+    nodes: Vec<BlockNode>,
+    /// Indices of top-level blocks in the program order
+    roots: Vec<usize>,
+}
+
+/// A single block of the [`BlockTree`].
+#[derive(Debug, PartialEq)]
+pub struct BlockNode {
+    info: BlockInfo,
+    /// An index of the enclosing block node
+    parent: Option<usize>,
+    /// Indices of directly nested block nodes in the program order
+    children: Vec<usize>,
+}
+
+impl BlockTree {
+    /// Returns the node with the specified index. Nodes are indexed in
+    /// post-order, i.e. in the order of 'End' instructions.
+    pub fn node(&self, idx: usize) -> Option<&BlockNode> {
+        self.nodes.get(idx)
+    }
+
+    /// Returns indices of top-level blocks in the program order.
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    /// Returns the number of blocks in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns true if the tree contains no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Iterates over all blocks in pre-order, i.e. a block goes before its
+    /// nested blocks, in the order of block starts.
+    pub fn pre_order(&self) -> PreOrder<'_> {
+        PreOrder {
+            tree: self,
+            stack: self.roots.iter().rev().copied().collect(),
+        }
+    }
+
+    /// Iterates over all blocks in post-order, i.e. a block goes after its
+    /// nested blocks, in the order of block ends.
+    pub fn post_order(&self) -> PostOrder<'_> {
+        PostOrder {
+            nodes: self.nodes.iter(),
+        }
+    }
+}
+
+impl BlockNode {
+    /// Returns the matched block.
+    pub fn info(&self) -> &BlockInfo {
+        &self.info
+    }
+
+    /// Returns an index of the first block instruction in the whole program.
+    pub fn start(&self) -> usize {
+        self.info.block_start_idx()
+    }
+
+    /// Returns an index of the last ('End') block instruction in the whole
+    /// program.
+    pub fn end(&self) -> usize {
+        self.info.block_end_idx()
+    }
+
+    /// Returns the number of blocks this block is nested into, top-level
+    /// blocks have depth 0.
+    pub fn depth(&self) -> usize {
+        self.info.depth()
+    }
+
+    /// Returns an index of this block in the registry, if the block is known.
+    pub fn registry_idx(&self) -> Option<usize> {
+        self.info.registry_idx()
+    }
+
+    /// Returns a node index of the enclosing block.
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    /// Returns node indices of directly nested blocks in the program order.
+    pub fn children(&self) -> &[usize] {
+        &self.children
+    }
+}
+
+/// A pre-order iterator over blocks of the [`BlockTree`].
+#[derive(Debug, Clone)]
+pub struct PreOrder<'a> {
+    tree: &'a BlockTree,
+    /// Indices of nodes to visit, the next one is the last
+    stack: Vec<usize>,
+}
+
+impl<'a> Iterator for PreOrder<'a> {
+    type Item = &'a BlockNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = &self.tree.nodes[self.stack.pop()?];
+        self.stack.extend(node.children.iter().rev());
+        Some(node)
+    }
+}
+
+/// A post-order iterator over blocks of the [`BlockTree`].
+#[derive(Debug, Clone)]
+pub struct PostOrder<'a> {
+    nodes: slice::Iter<'a, BlockNode>,
+}
+
+impl<'a> Iterator for PostOrder<'a> {
+    type Item = &'a BlockNode;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.nodes.next()
+    }
+}
+
+/// Builds the [`BlockTree`] from blocks which are opened and closed in the
+/// program order.
+#[derive(Debug, Default)]
+pub(crate) struct TreeBuilder {
+    tree: BlockTree,
+    /// Indices of already closed children of each opened block, the innermost
+    /// block is the last one
+    open: Vec<Vec<usize>>,
+}
+
+impl TreeBuilder {
+    /// Opens a new innermost block.
+    pub fn open(&mut self) {
+        self.open.push(Vec::new());
+    }
+
+    /// Closes the innermost block.
+    pub fn close(&mut self, info: BlockInfo) {
+        let children = self.open.pop().unwrap_or_default();
+        let idx = self.tree.nodes.len();
+        for &child in &children {
+            self.tree.nodes[child].parent = Some(idx);
+        }
+        self.tree.nodes.push(BlockNode {
+            info,
+            parent: None,
+            children,
+        });
+        match self.open.last_mut() {
+            Some(siblings) => siblings.push(idx),
+            None => self.tree.roots.push(idx),
+        }
+    }
+
+    /// Closes the innermost block as the first arm of a split block and opens
+    /// the next arm. Both arms are nested into the whole split block, which
+    /// is closed right after its last arm.
+    pub fn split(&mut self, arm: BlockInfo) {
+        self.close(arm);
+        let siblings = match self.open.last_mut() {
+            Some(siblings) => siblings,
+            None => &mut self.tree.roots,
+        };
+        let arm_idx = siblings.pop().expect("the arm is just closed");
+        self.open.push(vec![arm_idx]);
+        self.open();
+    }
+
+    pub fn finish(self) -> BlockTree {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BlockKind;
+    use crate::BlockRegistry;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn nested_blocks_tree() {
+        let registry = BlockRegistry::new(&[&[If, Push(Int(2)), End], &[Begin, End]]);
+        let program = vec![
+            Begin,
+            If,
+            Push(Int(2)),
+            End,
+            Begin,
+            End,
+            End,
+            If,
+            Push(Int(3)),
+            End,
+        ];
+
+        let tree = registry.find_block_tree(&program).unwrap();
+
+        assert_eq!(4, tree.len());
+        assert_eq!(&[2, 3], tree.roots());
+        let outer = tree.node(2).unwrap();
+        assert_eq!(0, outer.start());
+        assert_eq!(6, outer.end());
+        assert_eq!(0, outer.depth());
+        assert_eq!(None, outer.registry_idx());
+        assert_eq!(&[0, 1], outer.children());
+        let inner = tree.node(0).unwrap();
+        assert_eq!(1, inner.start());
+        assert_eq!(3, inner.end());
+        assert_eq!(1, inner.depth());
+        assert_eq!(Some(0), inner.registry_idx());
+        assert_eq!(Some(2), inner.parent());
+        assert_eq!(None, tree.node(3).unwrap().parent());
+    }
+
+    #[test]
+    fn arms_are_nested_into_whole_block() {
+        let registry = BlockRegistry::default();
+        let program = vec![Begin, If, Push(Int(1)), Else, If, End, End, End];
+
+        let tree = registry.find_block_tree(&program).unwrap();
+
+        let outer = tree.node(4).unwrap();
+        assert_eq!(&[3], outer.children());
+        let whole = tree.node(3).unwrap();
+        assert_eq!((1, 6), (whole.start(), whole.end()));
+        assert_eq!(BlockKind::If, whole.info().kind());
+        assert_eq!(&[0, 2], whole.children());
+        let else_arm = tree.node(2).unwrap();
+        assert_eq!((3, 6), (else_arm.start(), else_arm.end()));
+        assert_eq!(&[1], else_arm.children());
+        assert_eq!(Some(3), else_arm.parent());
+        assert_eq!(Some(3), tree.node(0).unwrap().parent());
+        assert_eq!(1, else_arm.depth());
+    }
+
+    #[test]
+    fn tree_iterators() {
+        let registry = BlockRegistry::default();
+        let program = vec![Begin, If, Begin, End, End, If, End, End, Begin, End];
+
+        let tree = registry.find_block_tree(&program).unwrap();
+
+        let pre_order: Vec<_> = tree.pre_order().map(|node| node.start()).collect();
+        let post_order: Vec<_> = tree.post_order().map(|node| node.start()).collect();
+        let flat: Vec<_> = registry
+            .find_matches(&program)
+            .unwrap()
+            .iter()
+            .map(|info| info.block_start_idx())
+            .collect();
+        assert_eq!(vec![0, 1, 2, 5, 8], pre_order);
+        assert_eq!(vec![2, 1, 5, 0, 8], post_order);
+        assert_eq!(flat, post_order);
+    }
+
+    #[test]
+    fn deep_tree() {
+        let depth = 100_000;
+        let mut program = vec![Begin; depth];
+        program.extend(vec![End; depth]);
+
+        let tree = BlockRegistry::default().find_block_tree(&program).unwrap();
+
+        assert_eq!(depth, tree.pre_order().count());
+        assert_eq!(depth - 1, tree.pre_order().last().unwrap().depth());
+    }
+}
@@ -0,0 +1,191 @@
+//!
+//! Typed operands of the 'Push' instruction. Values of different types never
+//! match each other, e.g. `true` isn't equal to `1`, and they are hashed with
+//! their type, so they never share a fingerprint either.
+//!
+//! A symbol name may contain any characters, but whitespace, `;` and `\` are
+//! escaped in the textual form as `\u{<hex code>}`, e.g. `$a\u{3b}b` is the
+//! name `a;b`. So a symbol is always a single word of the textual form.
+//!
+
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::asm::COMMENT;
+
+/// The prefix of a symbol in the textual form.
+const SYMBOL: char = '$';
+/// The prefix of an escaped character of a symbol name in the textual form.
+const ESCAPE: &str = "\\u{";
+/// The suffix of an escaped character of a symbol name in the textual form.
+const ESCAPE_END: char = '}';
+
+/// An operand of the 'Push' instruction.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Value {
+    Bool(bool),
+    Int(i64),
+    /// A named symbol, e.g. a variable reference
+    Symbol(String),
+}
+
+/// A type of [`Value`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ValueType {
+    Bool,
+    Int,
+    Symbol,
+}
+
+impl Value {
+    /// Returns the type of this value.
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Bool(_) => ValueType::Bool,
+            Value::Int(_) => ValueType::Int,
+            Value::Symbol(_) => ValueType::Symbol,
+        }
+    }
+
+    /// Parses the value from its textual form: `true` and `false` are
+    /// booleans, a decimal number with an optional minus sign is an integer and
+    /// a name prefixed with `$` is a symbol. Returns None for anything else,
+    /// including a symbol with an invalid escape.
+    pub(crate) fn parse(text: &str) -> Option<Value> {
+        match text {
+            "true" => Some(Value::Bool(true)),
+            "false" => Some(Value::Bool(false)),
+            _ => match text.strip_prefix(SYMBOL) {
+                Some("") => None,
+                Some(name) => unescape(name).map(Value::Symbol),
+                None => text.parse().ok().map(Value::Int),
+            },
+        }
+    }
+}
+
+/// Returns true if the character of a symbol name is escaped in the textual
+/// form, i.e. it would split the word or it is the escape itself.
+fn is_escaped(ch: char) -> bool {
+    ch.is_whitespace() || ch == COMMENT || ESCAPE.starts_with(ch)
+}
+
+/// Writes the symbol name with escaped characters, so the name is a single
+/// word of the textual form.
+pub(crate) fn write_name(f: &mut Formatter, name: &str) -> fmt::Result {
+    for ch in name.chars() {
+        if is_escaped(ch) {
+            write!(f, "{}{:x}{}", ESCAPE, ch as u32, ESCAPE_END)?;
+        } else {
+            write!(f, "{}", ch)?;
+        }
+    }
+    Ok(())
+}
+
+/// Replaces escaped characters of the symbol name with the characters
+/// themselves, returns None if an escape is invalid.
+pub(crate) fn unescape(text: &str) -> Option<String> {
+    let mut name = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(escape_idx) = rest.find(|ch| ESCAPE.starts_with(ch)) {
+        name.push_str(&rest[..escape_idx]);
+        let (code, tail) = rest[escape_idx..]
+            .strip_prefix(ESCAPE)?
+            .split_once(ESCAPE_END)?;
+        if !code.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return None;
+        }
+        name.push(
+            u32::from_str_radix(code, 16)
+                .ok()
+                .and_then(char::from_u32)?,
+        );
+        rest = tail;
+    }
+    name.push_str(rest);
+
+    Some(name)
+}
+
+impl ValueType {
+    /// Parses the type from its name, returns None for an unknown name.
+    pub(crate) fn parse(text: &str) -> Option<ValueType> {
+        match text {
+            "bool" => Some(ValueType::Bool),
+            "int" => Some(ValueType::Int),
+            "symbol" => Some(ValueType::Symbol),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Symbol(name) => {
+                write!(f, "{}", SYMBOL)?;
+                write_name(f, name)
+            }
+        }
+    }
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ValueType::Bool => write!(f, "bool"),
+            ValueType::Int => write!(f, "int"),
+            ValueType::Symbol => write!(f, "symbol"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Value;
+
+    #[test]
+    fn parse_and_print_round_trip() {
+        let values = vec![
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Int(0),
+            Value::Int(-42),
+            Value::Int(i64::MAX),
+            Value::Symbol("x".into()),
+            Value::Symbol("a;b".into()),
+            Value::Symbol("x y\t\n".into()),
+            Value::Symbol("\\u{3b}".into()),
+        ];
+
+        for value in values {
+            assert_eq!(Some(value.clone()), Value::parse(&value.to_string()));
+        }
+        assert_eq!("$a\\u{3b}b", Value::Symbol("a;b".into()).to_string());
+        assert_eq!(None, Value::parse("$"));
+        assert_eq!(None, Value::parse("$a\\b"));
+        assert_eq!(None, Value::parse("$a\\u{3b"));
+        assert_eq!(None, Value::parse("$a\\u{+3b}"));
+        assert_eq!(None, Value::parse("$a\\u{d800}"));
+        assert_eq!(None, Value::parse("yes"));
+        assert_eq!(None, Value::parse("9223372036854775808"));
+    }
+}
@@ -0,0 +1,606 @@
+//!
+//! A stack machine which executes programs.
+//!
+//! Values are typed as operands of 'Push' are, logical instructions take only
+//! booleans, any other value is a type error for them:
+//!
+//! * Push(v) - pushes v onto the stack.
+//! * Or, And, Xor - pop two booleans and push the result.
+//! * Not - pops a boolean and pushes its negation.
+//! * Dup - pushes a copy of the top value.
+//! * Pop - pops the top value.
+//! * Swap - swaps two top values.
+//! * If - pops a boolean condition, if it is false the whole block up to the
+//!   corresponded 'Else' or 'End' is skipped.
+//! * Else - closes the 'If' block and opens the block which is executed only
+//!   if the condition was false, i.e. the block up to the corresponded 'End'
+//!   is skipped when 'Else' is reached.
+//! * Begin - opens a block which is always executed.
+//! * Loop - opens a block which is executed again and again, until 'Break'.
+//! * Break - skips the rest of the innermost 'Loop' block and leaves it.
+//! * End - closes the innermost block, goes back to the start of the block if
+//!   it is a 'Loop' block, does nothing else.
+//! * Call(n) - can't be executed, the program has to be expanded first, see
+//!   [`expand_calls`](crate::expand_calls).
+//!
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+
+use crate::delimiter::BlockDelimiter;
+use crate::delimiter::Delimiter;
+use crate::Instruction;
+use crate::Value;
+
+/// The greatest number of instructions executed by [`validate_block`].
+const VALIDATION_STEP_LIMIT: usize = 100_000;
+
+/// The stack machine.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Vm {
+    stack: Vec<Value>,
+    /// The greatest number of executed instructions of a single run
+    step_limit: Option<usize>,
+}
+
+/// An error occurred while executing a program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmError {
+    /// An index of the failed instruction in the program
+    pub position: usize,
+    pub kind: VmErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmErrorKind {
+    /// The instruction needs more values than the stack has.
+    StackUnderflow,
+    /// The instruction needs a boolean, but got another value.
+    TypeError { value: Value },
+    /// 'End' or 'Else' closes a block which wasn't opened, 'Else' can close
+    /// only an 'If' block.
+    UnmatchedEnd,
+    /// The block opened by the instruction is never closed.
+    UnclosedBlock,
+    /// Instructions are expected to be exactly one block, but they aren't.
+    NotSingleBlock,
+    /// 'Break' isn't inside any 'Loop' block.
+    BreakOutsideLoop,
+    /// The program executed more instructions than the step limit allows,
+    /// the position is of the next instruction.
+    StepLimitExceeded,
+    /// 'Call' refers to a registry block, which the machine doesn't know.
+    UnexpandedCall,
+}
+
+impl Vm {
+    /// Creates the machine with the specified initial stack, the top value is
+    /// the last one.
+    pub fn with_stack(stack: Vec<Value>) -> Self {
+        Vm {
+            stack,
+            step_limit: None,
+        }
+    }
+
+    /// Limits the number of instructions executed by a single run, so a
+    /// program with an endless loop fails instead of hanging.
+    pub fn with_step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = Some(step_limit);
+        self
+    }
+
+    /// Returns the current stack, the top value is the last one.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Executes the program. Blocks of the program have to be balanced,
+    /// otherwise nothing is executed. If an instruction fails, the stack is
+    /// left as it was before that instruction.
+    pub fn run(&mut self, program: &[Instruction]) -> Result<(), VmError> {
+        use crate::Instruction::*;
+
+        let jumps = jumps(program)?;
+        let mut position = 0;
+        let mut steps = 0;
+
+        while let Some(instruction) = program.get(position) {
+            if self.step_limit.is_some_and(|limit| steps >= limit) {
+                return Err(VmError {
+                    position,
+                    kind: VmErrorKind::StepLimitExceeded,
+                });
+            }
+            steps += 1;
+
+            match instruction {
+                Push(value) => self.stack.push(value.clone()),
+                Or | And | Xor => {
+                    let right = self.pop_bool(position)?;
+                    let left = match self.pop_bool(position) {
+                        Ok(left) => left,
+                        Err(err) => {
+                            self.stack.push(Value::Bool(right));
+                            return Err(err);
+                        }
+                    };
+                    let result = match instruction {
+                        Or => left || right,
+                        And => left && right,
+                        _ => left != right,
+                    };
+                    self.stack.push(Value::Bool(result));
+                }
+                Not => {
+                    let value = self.pop_bool(position)?;
+                    self.stack.push(Value::Bool(!value));
+                }
+                Dup => {
+                    let value = self.top(position, 1)?[0].clone();
+                    self.stack.push(value);
+                }
+                Pop => {
+                    self.top(position, 1)?;
+                    self.stack.pop();
+                }
+                Swap => self.top(position, 2)?.swap(0, 1),
+                If => {
+                    if !self.pop_bool(position)? {
+                        position = jumps[position];
+                    }
+                }
+                Else | End | Break => position = jumps[position],
+                Begin | Loop => {} // do nothing
+                Call(_) => {
+                    return Err(VmError {
+                        position,
+                        kind: VmErrorKind::UnexpandedCall,
+                    })
+                }
+            }
+            position += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Pops a boolean from the stack, a value which isn't a boolean is left
+    /// on the stack.
+    fn pop_bool(&mut self, position: usize) -> Result<bool, VmError> {
+        let kind = match self.stack.last() {
+            Some(&Value::Bool(value)) => {
+                self.stack.pop();
+                return Ok(value);
+            }
+            Some(value) => VmErrorKind::TypeError {
+                value: value.clone(),
+            },
+            None => VmErrorKind::StackUnderflow,
+        };
+        Err(VmError { position, kind })
+    }
+
+    /// Returns the specified number of top values of the stack.
+    fn top(&mut self, position: usize, len: usize) -> Result<&mut [Value], VmError> {
+        match self.stack.len().checked_sub(len) {
+            Some(start) => Ok(&mut self.stack[start..]),
+            None => Err(VmError {
+                position,
+                kind: VmErrorKind::StackUnderflow,
+            }),
+        }
+    }
+}
+
+/// Checks that the block is exactly one well-formed block, which can be
+/// executed without errors. The block is executed on an empty stack, but a
+/// block which starts with 'If' gets true as its condition, so its body is
+/// executed too.
+///
+/// An arm of 'If' is a block as well, i.e. 'If' up to the corresponded 'Else'
+/// and 'Else' up to the corresponded 'End' are executed as they are 'If' and
+/// 'Begin' blocks respectively, the whole 'If' block with both arms is a
+/// block too. A loop which doesn't end after a lot of steps is an error.
+pub fn validate_block(block: &[Instruction]) -> Result<(), VmError> {
+    use crate::Instruction::*;
+
+    let mut block = block.to_vec();
+    if let Some(first) = block.first_mut().filter(|first| **first == Else) {
+        *first = Begin;
+    }
+    if let Some(last) = block.last_mut().filter(|last| **last == Else) {
+        *last = End;
+    }
+
+    let jumps = jumps(&block)?;
+    let end = match block.first() {
+        // the first arm jumps to its 'Else', which jumps to the 'End'
+        Some(If) if block[jumps[0]] == Else => Some(jumps[jumps[0]]),
+        Some(Begin) | Some(If) | Some(Loop) => Some(jumps[0]),
+        _ => None,
+    };
+    if end.is_none_or(|end| end + 1 != block.len()) {
+        return Err(VmError {
+            position: 0,
+            kind: VmErrorKind::NotSingleBlock,
+        });
+    }
+
+    let vm = match block.first() {
+        Some(If) => Vm::with_stack(vec![Value::Bool(true)]),
+        _ => Vm::default(),
+    };
+    vm.with_step_limit(VALIDATION_STEP_LIMIT).run(&block)
+}
+
+/// A block which is opened, but not closed yet, while jumps are computed.
+struct OpenBlock<'a> {
+    start: usize,
+    opener: &'a Instruction,
+    /// Positions of 'Break' instructions which leave this block
+    breaks: Vec<usize>,
+}
+
+/// Returns positions the execution jumps to from each instruction, the next
+/// instruction after the jump is executed:
+///
+/// * If - the corresponded 'Else' or 'End', the jump is taken if the
+///   condition is false.
+/// * Else - the corresponded 'End'.
+/// * End of a 'Loop' block - the 'Loop'.
+/// * Break - the 'End' of the innermost 'Loop' block.
+///
+/// For other instructions the position is the instruction itself.
+fn jumps(program: &[Instruction]) -> Result<Vec<usize>, VmError> {
+    let mut jumps: Vec<usize> = (0..program.len()).collect();
+    let mut open_blocks: Vec<OpenBlock> = Vec::new();
+    let unmatched_end = |position| VmError {
+        position,
+        kind: VmErrorKind::UnmatchedEnd,
+    };
+
+    for (position, instruction) in program.iter().enumerate() {
+        match instruction.delimiter() {
+            Delimiter::Open => open_blocks.push(OpenBlock {
+                start: position,
+                opener: instruction,
+                breaks: Vec::new(),
+            }),
+            Delimiter::Split | Delimiter::Close => {
+                let block = match open_blocks.pop() {
+                    Some(block) if instruction.closes(block.opener) => block,
+                    _ => return Err(unmatched_end(position)),
+                };
+                jumps[block.start] = position;
+                if *block.opener == Instruction::Loop {
+                    jumps[position] = block.start;
+                    for break_position in block.breaks {
+                        jumps[break_position] = position;
+                    }
+                }
+                if instruction.delimiter() == Delimiter::Split {
+                    open_blocks.push(OpenBlock {
+                        start: position,
+                        opener: instruction,
+                        breaks: Vec::new(),
+                    });
+                }
+            }
+            Delimiter::Neutral if *instruction == Instruction::Break => {
+                match open_blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|block| *block.opener == Instruction::Loop)
+                {
+                    Some(block) => block.breaks.push(position),
+                    None => {
+                        return Err(VmError {
+                            position,
+                            kind: VmErrorKind::BreakOutsideLoop,
+                        })
+                    }
+                }
+            }
+            Delimiter::Neutral => {}
+        }
+    }
+
+    match open_blocks.first() {
+        Some(block) => Err(VmError {
+            position: block.start,
+            kind: VmErrorKind::UnclosedBlock,
+        }),
+        None => Ok(jumps),
+    }
+}
+
+impl Error for VmError {}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.kind {
+            VmErrorKind::StackUnderflow => {
+                write!(f, "Stack underflow, at the position: {}", self.position)
+            }
+            VmErrorKind::TypeError { value } => write!(
+                f,
+                "Expected a boolean, but got {}, at the position: {}",
+                value, self.position
+            ),
+            VmErrorKind::UnmatchedEnd => write!(
+                f,
+                "Attempt to close the non-existent block, at the position: {}",
+                self.position
+            ),
+            VmErrorKind::UnclosedBlock => write!(
+                f,
+                "The block wasn't be closed, at the position: {}",
+                self.position
+            ),
+            VmErrorKind::NotSingleBlock => write!(f, "Instructions aren't exactly one block"),
+            VmErrorKind::BreakOutsideLoop => write!(
+                f,
+                "Attempt to break out of the non-existent loop, at the position: {}",
+                self.position
+            ),
+            VmErrorKind::StepLimitExceeded => write!(
+                f,
+                "The step limit is exceeded, at the position: {}",
+                self.position
+            ),
+            VmErrorKind::UnexpandedCall => write!(
+                f,
+                "Attempt to call the unknown block, at the position: {}",
+                self.position
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::validate_block;
+    use crate::vm::Vm;
+    use crate::vm::VmError;
+    use crate::vm::VmErrorKind;
+    use crate::Instruction::*;
+    use crate::Value::*;
+
+    #[test]
+    fn boolean_operations() {
+        let mut vm = Vm::default();
+
+        vm.run(&[
+            Push(Bool(true)),
+            Push(Bool(false)),
+            Or,
+            Push(Bool(true)),
+            And,
+            Not,
+            Push(Int(7)),
+        ])
+        .unwrap();
+
+        assert_eq!(&[Bool(false), Int(7)], vm.stack());
+    }
+
+    #[test]
+    fn conditional_blocks() {
+        let mut vm = Vm::default();
+
+        vm.run(&[
+            Push(Bool(false)),
+            If,
+            Push(Int(2)),
+            If,
+            End,
+            End,
+            Push(Bool(true)),
+            If,
+            Begin,
+            Push(Int(3)),
+            End,
+            End,
+        ])
+        .unwrap();
+
+        assert_eq!(&[Int(3)], vm.stack());
+    }
+
+    #[test]
+    fn stack_operations() {
+        let mut vm = Vm::default();
+
+        vm.run(&[
+            Push(Bool(true)),
+            Push(Bool(false)),
+            Xor,
+            Push(Int(7)),
+            Swap,
+            Dup,
+            Push(Int(3)),
+            Pop,
+        ])
+        .unwrap();
+
+        assert_eq!(&[Int(7), Bool(true), Bool(true)], vm.stack());
+        assert_eq!(
+            Err(VmErrorKind::StackUnderflow),
+            Vm::with_stack(vec![Bool(true)])
+                .run(&[Swap])
+                .map_err(|err| err.kind)
+        );
+    }
+
+    #[test]
+    fn else_blocks() {
+        let program = [If, Push(Int(2)), Else, Push(Int(3)), End, Push(Int(4))];
+
+        let mut then_vm = Vm::with_stack(vec![Bool(true)]);
+        then_vm.run(&program).unwrap();
+        let mut else_vm = Vm::with_stack(vec![Bool(false)]);
+        else_vm.run(&program).unwrap();
+
+        assert_eq!(&[Int(2), Int(4)], then_vm.stack());
+        assert_eq!(&[Int(3), Int(4)], else_vm.stack());
+    }
+
+    #[test]
+    fn loop_blocks() {
+        let mut vm = Vm::default();
+
+        // pops conditions until the false one
+        vm.run(&[
+            Push(Bool(false)),
+            Push(Bool(true)),
+            Push(Bool(true)),
+            Loop,
+            If,
+            Else,
+            Break,
+            End,
+            End,
+            Push(Int(9)),
+        ])
+        .unwrap();
+
+        assert_eq!(&[Int(9)], vm.stack());
+    }
+
+    #[test]
+    fn endless_loop() {
+        let mut vm = Vm::default().with_step_limit(100);
+
+        let result = vm.run(&[Loop, Push(Bool(true)), Pop, End]);
+
+        assert_eq!(
+            Err(VmError {
+                position: 1,
+                kind: VmErrorKind::StepLimitExceeded,
+            }),
+            result
+        );
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let mut vm = Vm::default();
+
+        let result = vm.run(&[Loop, End, Begin, Break, End]);
+
+        assert_eq!(
+            Err(VmError {
+                position: 3,
+                kind: VmErrorKind::BreakOutsideLoop,
+            }),
+            result
+        );
+    }
+
+    #[test]
+    fn unexpanded_call() {
+        let mut vm = Vm::default();
+
+        let result = vm.run(&[Push(Bool(true)), Call(0)]);
+
+        assert_eq!(
+            Err(VmError {
+                position: 1,
+                kind: VmErrorKind::UnexpandedCall,
+            }),
+            result
+        );
+        assert_eq!(&[Bool(true)], vm.stack());
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut vm = Vm::default();
+
+        let result = vm.run(&[Push(Bool(true)), And]);
+
+        assert_eq!(
+            Err(VmError {
+                position: 1,
+                kind: VmErrorKind::StackUnderflow,
+            }),
+            result
+        );
+        assert_eq!(&[Bool(true)], vm.stack());
+    }
+
+    #[test]
+    fn type_error() {
+        let mut vm = Vm::default();
+
+        let result = vm.run(&[Push(Bool(true)), Push(Int(2)), Or]);
+
+        assert_eq!(
+            "Expected a boolean, but got 2, at the position: 2",
+            result.unwrap_err().to_string()
+        );
+        assert_eq!(&[Bool(true), Int(2)], vm.stack());
+        assert_eq!(
+            Err(VmErrorKind::TypeError {
+                value: Symbol("x".into())
+            }),
+            Vm::default()
+                .run(&[Push(Symbol("x".into())), Not])
+                .map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(VmErrorKind::TypeError { value: Int(1) }),
+            Vm::default()
+                .run(&[Push(Int(1)), If, End])
+                .map_err(|err| err.kind)
+        );
+    }
+
+    #[test]
+    fn block_validation() {
+        assert_eq!(Ok(()), validate_block(&[If, Push(Bool(true)), Not, End]));
+        assert_eq!(
+            Ok(()),
+            validate_block(&[Begin, Push(Bool(true)), If, End, End])
+        );
+        assert_eq!(
+            Err(VmErrorKind::StackUnderflow),
+            validate_block(&[Begin, If, End, End]).map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(VmErrorKind::NotSingleBlock),
+            validate_block(&[If, End, If, End]).map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(VmErrorKind::NotSingleBlock),
+            validate_block(&[]).map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(VmErrorKind::UnclosedBlock),
+            validate_block(&[If, Begin, End]).map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(VmErrorKind::UnmatchedEnd),
+            validate_block(&[Begin, End, End]).map_err(|err| err.kind)
+        );
+    }
+
+    #[test]
+    fn arm_validation() {
+        assert_eq!(Ok(()), validate_block(&[If, Push(Bool(true)), Else]));
+        assert_eq!(Ok(()), validate_block(&[Else, Push(Bool(true)), Not, End]));
+        assert_eq!(Ok(()), validate_block(&[If, Push(Int(1)), Else, Pop, End]));
+        assert_eq!(Ok(()), validate_block(&[Loop, Break, End]));
+        assert_eq!(
+            Err(VmErrorKind::UnmatchedEnd),
+            validate_block(&[Begin, Else, End]).map_err(|err| err.kind)
+        );
+        assert_eq!(
+            Err(VmErrorKind::StepLimitExceeded),
+            validate_block(&[Loop, End]).map_err(|err| err.kind)
+        );
+    }
+}